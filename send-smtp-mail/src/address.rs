@@ -0,0 +1,281 @@
+use crate::mime::header;
+use std::fmt;
+use std::io;
+
+// RFC 5321 section 4.5.3.1 size limits
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_ADDRESS_LENGTH: usize = 254;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// A mail address with an optional display name, e.g. `"Ops Bot" <ops@example.com>`.
+/// The address is validated on construction so it is always safe to use in
+/// MAIL FROM and RCPT TO.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Mailbox {
+    name: Option<String>,
+    address: String,
+}
+
+impl Mailbox {
+    pub fn new(name: Option<&str>, address: &str) -> io::Result<Mailbox> {
+        let address = address.trim();
+        validate(address)?;
+        if let Some(name) = name.filter(|n| n.contains(['\r', '\n'])) {
+            return Err(invalid(&format!(
+                "Display name contains a line break: {name:?}"
+            )));
+        }
+        Ok(Mailbox {
+            name: name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty()),
+            address: address.to_string(),
+        })
+    }
+
+    /// Parse `addr@example.com`, `<addr@example.com>`, `Name <addr@example.com>`
+    /// or `"Last, First" <addr@example.com>`
+    pub fn parse(text: &str) -> io::Result<Mailbox> {
+        let text = text.trim();
+        let Some(open) = text.rfind('<') else {
+            return Mailbox::new(None, text);
+        };
+        let Some(address) = text[open + 1..].strip_suffix('>') else {
+            return Err(invalid(&format!("Missing '>' in address {text:?}")));
+        };
+        let name = text[..open].trim();
+        let name = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+            Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+            None => name.to_string(),
+        };
+        Mailbox::new(Some(&name), address)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Form used in From/To/Cc headers, non-ASCII display names as encoded words
+    pub fn to_header(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} <{}>", header::encode_phrase(name), self.address),
+            None => self.address.clone(),
+        }
+    }
+
+    /// Form used in headers of a message sent with SMTPUTF8, the name as UTF-8
+    pub fn to_utf8_header(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} <{}>", header::utf8_phrase(name), self.address),
+            None => self.address.clone(),
+        }
+    }
+
+    /// Needs SMTPUTF8 in the envelope (RFC 6531)
+    pub fn is_utf8(&self) -> bool {
+        !self.address.is_ascii()
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} <{}>", name, self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+/// Parse a list of mailboxes, e.g. from the comma separated smtp_to setting.
+/// Commas inside a quoted display name do not split. Groups as found in message
+/// headers, `Team: a@example.com, b@example.com;` or `undisclosed-recipients:;`,
+/// give their members.
+pub fn parse_list(text: &str) -> io::Result<Vec<Mailbox>> {
+    let mut mailboxes = Vec::new();
+    let mut quoted = false;
+    let mut nested = 0; // inside <...> or an [address literal]
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' | '[' if !quoted => nested += 1,
+            '>' | ']' if !quoted => nested -= 1,
+            // group name, the members follow
+            ':' if !quoted && nested == 0 => start = i + 1,
+            ',' | ';' if !quoted && nested == 0 => {
+                if !text[start..i].trim().is_empty() {
+                    mailboxes.push(Mailbox::parse(&text[start..i])?);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        mailboxes.push(Mailbox::parse(&text[start..])?);
+    }
+    Ok(mailboxes)
+}
+
+/// Check an address for use in the SMTP envelope: local@domain, dot-atom or quoted
+/// local part, domain name or address literal. UTF-8 is accepted (RFC 6531), whether
+/// the server allows it is decided when sending.
+pub fn validate(address: &str) -> io::Result<()> {
+    let fail = |reason: &str| Err(invalid(&format!("Invalid address {address:?}: {reason}")));
+    if address.is_empty() {
+        return fail("empty");
+    }
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return fail("longer than 254 characters");
+    }
+    if address.chars().any(|c| c.is_control()) {
+        return fail("contains control characters");
+    }
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        return fail("missing '@'");
+    };
+    if local.is_empty() || local.len() > MAX_LOCAL_PART_LENGTH {
+        return fail("local part must be 1 to 64 characters");
+    }
+    if !is_dot_atom(local) && !is_quoted_string(local) {
+        return fail("invalid character or dot in local part");
+    }
+    if domain.starts_with('[') && domain.ends_with(']') {
+        let literal = &domain[1..domain.len() - 1];
+        let literal = literal.strip_prefix("IPv6:").unwrap_or(literal);
+        return match literal.parse::<std::net::IpAddr>() {
+            Ok(_) => Ok(()),
+            Err(_) => fail("invalid address literal"),
+        };
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    let label_ok = |l: &&str| {
+        !l.is_empty()
+            && l.len() <= MAX_LABEL_LENGTH
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.chars().all(|c| c.is_alphanumeric() || c == '-')
+    };
+    if !labels.iter().all(label_ok) {
+        return fail("invalid domain");
+    }
+    Ok(())
+}
+
+fn is_dot_atom(text: &str) -> bool {
+    let atext =
+        |c: char| !c.is_ascii() || c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    text.split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(atext))
+}
+
+// "quoted local part", a quote or backslash inside must be escaped with a backslash
+fn is_quoted_string(text: &str) -> bool {
+    let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) else {
+        return false;
+    };
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.next().is_none() => return false,
+            '"' => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(text: &str) -> Vec<String> {
+        let list = parse_list(text).unwrap();
+        list.iter().map(|m| m.address().to_string()).collect()
+    }
+
+    #[test]
+    fn parse_forms() {
+        let mailbox = Mailbox::parse("\"Last, First \\\"Q\\\"\" <a@example.com>").unwrap();
+        assert_eq!(mailbox.name(), Some("Last, First \"Q\""));
+        assert_eq!(mailbox.address(), "a@example.com");
+        assert_eq!(Mailbox::parse("<a@example.com>").unwrap().name(), None);
+        assert_eq!(
+            Mailbox::parse(" a@example.com ").unwrap().address(),
+            "a@example.com"
+        );
+        assert!(Mailbox::parse("Name <a@example.com").is_err());
+        assert!(Mailbox::new(Some("a\r\nBcc: x@example.com"), "a@example.com").is_err());
+    }
+
+    #[test]
+    fn parse_list_quoted_commas_and_groups() {
+        assert_eq!(
+            addresses("\"Last, First\" <a@example.com>, b@example.com"),
+            ["a@example.com", "b@example.com"]
+        );
+        assert_eq!(
+            addresses("Team: a@example.com, \"x:y\" <b@example.com>;, c@example.com"),
+            ["a@example.com", "b@example.com", "c@example.com"]
+        );
+        assert!(addresses("undisclosed-recipients:;").is_empty());
+        assert!(addresses(" , ").is_empty());
+        assert!(parse_list("a@example.com, not an address").is_err());
+    }
+
+    #[test]
+    fn validate_accepts() {
+        for address in [
+            "user+tag@example.com",
+            "first.last@sub.example.com",
+            "\"john doe\"@example.com",
+            "\"a\\\"b\"@example.com",
+            "a@[192.0.2.1]",
+            "a@[IPv6:2001:db8::1]",
+            "jörg@exämple.de",
+        ] {
+            assert!(validate(address).is_ok(), "{address}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects() {
+        let long_local = format!("{}@example.com", "a".repeat(65));
+        for address in [
+            "",
+            "example.com",
+            "@example.com",
+            "a..b@example.com",
+            ".a@example.com",
+            "a b@example.com",
+            "a\r\n@example.com",
+            "\"a\"b\"@x.com",
+            "\"a\\\"@x.com",
+            "a@-example.com",
+            "a@example..com",
+            "a@[300.1.1.1]",
+            &long_local,
+        ] {
+            assert!(validate(address).is_err(), "{address:?}");
+        }
+        assert!(Mailbox::parse("\"a\"b\"@x.com").is_err());
+    }
+
+    #[test]
+    fn header_forms() {
+        let mailbox = Mailbox::parse("\"Ops, Bot\" <ops@example.com>").unwrap();
+        assert_eq!(mailbox.to_header(), "\"Ops, Bot\" <ops@example.com>");
+        let mailbox = Mailbox::parse("Jörg Müller <j@example.com>").unwrap();
+        assert_eq!(
+            mailbox.to_header(),
+            "=?utf-8?q?J=C3=B6rg_M=C3=BCller?= <j@example.com>"
+        );
+        assert_eq!(mailbox.to_utf8_header(), "Jörg Müller <j@example.com>");
+    }
+}
@@ -0,0 +1,294 @@
+use crate::secret::Secret;
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine; // trait
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::io;
+use zeroize::Zeroizing;
+
+/// SASL mechanisms supported for SMTP AUTH (RFC 4954)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mechanism {
+    Plain,       // RFC 4616, credentials sent as initial response
+    Login,       // draft-murchison-sasl-login, username and password prompts
+    CramMd5,     // RFC 2195, password never sent over the wire
+    XOAuth2,     // Google / Microsoft OAuth 2.0 bearer token
+    OAuthBearer, // RFC 7628 standard version of XOAUTH2
+}
+
+impl Mechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::Login => "LOGIN",
+            Mechanism::CramMd5 => "CRAM-MD5",
+            Mechanism::XOAuth2 => "XOAUTH2",
+            Mechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+
+    /// OAuth mechanisms take an access token instead of a password
+    pub fn is_oauth(&self) -> bool {
+        matches!(self, Mechanism::XOAuth2 | Mechanism::OAuthBearer)
+    }
+
+    pub fn from_name(name: &str) -> Option<Mechanism> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "PLAIN" => Some(Mechanism::Plain),
+            "LOGIN" => Some(Mechanism::Login),
+            "CRAM-MD5" | "CRAMMD5" => Some(Mechanism::CramMd5),
+            "XOAUTH2" => Some(Mechanism::XOAuth2),
+            "OAUTHBEARER" => Some(Mechanism::OAuthBearer),
+            _ => None,
+        }
+    }
+
+    /// Pick the mechanism to use from the ones the server advertised.
+    /// A forced mechanism is used even if not advertised, some servers omit it from EHLO.
+    /// On an unencrypted connection CRAM-MD5 is preferred as it does not reveal the password.
+    /// With an OAuth token only the OAuth mechanisms are considered.
+    pub fn select(
+        advertised: &[String],
+        forced: Option<Mechanism>,
+        encrypted: bool,
+        oauth: bool,
+    ) -> Option<Mechanism> {
+        if forced.is_some() {
+            return forced;
+        }
+        let preference: &[Mechanism] = if oauth {
+            &[Mechanism::OAuthBearer, Mechanism::XOAuth2]
+        } else if encrypted {
+            &[Mechanism::Plain, Mechanism::Login, Mechanism::CramMd5]
+        } else {
+            &[Mechanism::CramMd5, Mechanism::Plain, Mechanism::Login]
+        };
+        preference
+            .iter()
+            .find(|m| advertised.iter().any(|a| a.eq_ignore_ascii_case(m.name())))
+            .copied()
+    }
+}
+
+/// Drives one AUTH exchange, producing the response line for each 334 challenge.
+/// The lines carry the credentials, they are returned as secrets and not logged.
+pub struct Authenticator {
+    pub mechanism: Mechanism,
+    username: String,
+    secret: Secret, // password, or access token for the OAuth mechanisms
+    server: Option<(String, u16)>, // host and port sent in OAUTHBEARER
+    step: usize,    // number of challenges answered so far
+}
+
+impl Authenticator {
+    pub fn new(mechanism: Mechanism, username: &str, secret: Secret) -> Self {
+        Authenticator {
+            mechanism,
+            username: username.to_string(),
+            secret,
+            server: None,
+            step: 0,
+        }
+    }
+
+    pub fn with_server(mut self, host: &str, port: u16) -> Self {
+        self.server = Some((host.to_string(), port));
+        self
+    }
+
+    /// The AUTH command line, including the initial response where the mechanism allows it
+    pub fn initial_command(&mut self) -> Secret {
+        let command = match self.mechanism {
+            Mechanism::Plain => {
+                self.step += 1;
+                format!("AUTH PLAIN {}", self.plain_response().expose())
+            }
+            Mechanism::XOAuth2 => {
+                self.step += 1;
+                let response = Zeroizing::new(format!(
+                    "user={}\x01auth=Bearer {}\x01\x01",
+                    self.username,
+                    self.secret.expose()
+                ));
+                format!("AUTH XOAUTH2 {}", b64.encode(response.as_bytes()))
+            }
+            Mechanism::OAuthBearer => {
+                self.step += 1;
+                let server = match &self.server {
+                    Some((host, port)) => format!("host={host}\x01port={port}\x01"),
+                    None => String::new(),
+                };
+                // gs2 header, the authzid "a=" escapes ',' and '=' (RFC 5801)
+                let user = self.username.replace('=', "=3D").replace(',', "=2C");
+                let response = Zeroizing::new(format!(
+                    "n,a={user},\x01{server}auth=Bearer {}\x01\x01",
+                    self.secret.expose()
+                ));
+                format!("AUTH OAUTHBEARER {}", b64.encode(response.as_bytes()))
+            }
+            m => format!("AUTH {}", m.name()),
+        };
+        Secret::new(command)
+    }
+
+    /// Answer a 334 challenge, `challenge` is the base64 text of the reply
+    pub fn respond(&mut self, challenge: &str) -> io::Result<Secret> {
+        let challenge = b64.decode(challenge.trim()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("AUTH challenge is not valid base64 {:?}: {}", challenge, e),
+            )
+        })?;
+        let prompt = String::from_utf8_lossy(&challenge).to_ascii_lowercase();
+        log::debug!("AUTH {} challenge: {:?}", self.mechanism.name(), prompt);
+        self.step += 1;
+        let response = match self.mechanism {
+            // Empty challenge, the server did not accept an initial response
+            Mechanism::Plain if self.step <= 2 && challenge.is_empty() => {
+                return Ok(self.plain_response())
+            }
+            Mechanism::Plain => return Err(unexpected_challenge(self.mechanism, &prompt)),
+            // A 334 after the token is the base64 JSON error, the client must answer with an
+            // empty line (XOAUTH2) or ^A (OAUTHBEARER) before the server sends the final 535
+            Mechanism::XOAuth2 | Mechanism::OAuthBearer => {
+                log::error!(
+                    "AUTH {} token rejected: {}",
+                    self.mechanism.name(),
+                    crate::oauth::describe_error(&challenge)
+                );
+                return Ok(Secret::new(match self.mechanism {
+                    Mechanism::OAuthBearer => b64.encode("\x01"),
+                    _ => String::new(),
+                }));
+            }
+            // Prompts are usually "Username:" and "Password:" but servers vary, fall back to order
+            Mechanism::Login if prompt.contains("pass") => self.secret.clone(),
+            Mechanism::Login if prompt.contains("user") => Secret::new(self.username.clone()),
+            Mechanism::Login if self.step == 1 => Secret::new(self.username.clone()),
+            Mechanism::Login if self.step == 2 => self.secret.clone(),
+            Mechanism::Login => return Err(unexpected_challenge(self.mechanism, &prompt)),
+            Mechanism::CramMd5 if self.step == 1 => {
+                let mut mac = Hmac::<Md5>::new_from_slice(self.secret.expose().as_bytes())
+                    .expect("HMAC accepts any key length");
+                mac.update(&challenge);
+                let digest: String = mac
+                    .finalize()
+                    .into_bytes()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                Secret::new(format!("{} {}", self.username, digest))
+            }
+            Mechanism::CramMd5 => return Err(unexpected_challenge(self.mechanism, &prompt)),
+        };
+        Ok(Secret::new(b64.encode(response.expose())))
+    }
+
+    fn plain_response(&self) -> Secret {
+        // authzid NUL authcid NUL passwd, empty authzid
+        let response = Zeroizing::new(format!("\0{}\0{}", self.username, self.secret.expose()));
+        Secret::new(b64.encode(response.as_bytes()))
+    }
+}
+
+fn unexpected_challenge(mechanism: Mechanism, prompt: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Unexpected AUTH {} challenge: {:?}",
+            mechanism.name(),
+            prompt
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    // answer a challenge given as text, the decoded response
+    fn respond(authenticator: &mut Authenticator, challenge: &str) -> String {
+        let response = authenticator.respond(&b64.encode(challenge)).unwrap();
+        String::from_utf8(b64.decode(response.expose()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn cram_md5_rfc2195_example() {
+        let secret = Secret::new("tanstaaftanstaaf".to_string());
+        let mut authenticator = Authenticator::new(Mechanism::CramMd5, "tim", secret);
+        assert_eq!(authenticator.initial_command().expose(), "AUTH CRAM-MD5");
+        let response = respond(
+            &mut authenticator,
+            "<1896.697170952@postoffice.reston.mci.net>",
+        );
+        assert_eq!(response, "tim b913a602c7eda7a495b4e6e7334d3890");
+        assert!(authenticator.respond(&b64.encode("again")).is_err());
+    }
+
+    #[test]
+    fn plain_initial_response() {
+        let secret = Secret::new("secret".to_string());
+        let mut authenticator = Authenticator::new(Mechanism::Plain, "user", secret);
+        let command = authenticator.initial_command();
+        let response = command.expose().strip_prefix("AUTH PLAIN ").unwrap();
+        assert_eq!(b64.decode(response).unwrap(), b"\0user\0secret");
+        // a server that ignores the initial response asks with an empty challenge
+        assert_eq!(respond(&mut authenticator, ""), "\0user\0secret");
+    }
+
+    #[test]
+    fn login_prompts() {
+        let secret = Secret::new("secret".to_string());
+        let mut authenticator = Authenticator::new(Mechanism::Login, "user", secret);
+        assert_eq!(authenticator.initial_command().expose(), "AUTH LOGIN");
+        assert_eq!(respond(&mut authenticator, "Username:"), "user");
+        assert_eq!(respond(&mut authenticator, "Password:"), "secret");
+        // prompts in any order are recognised by their text
+        let secret = Secret::new("secret".to_string());
+        let mut authenticator = Authenticator::new(Mechanism::Login, "user", secret);
+        assert_eq!(respond(&mut authenticator, "Password:"), "secret");
+    }
+
+    #[test]
+    fn xoauth2_initial_response() {
+        let token = Secret::new("token".to_string());
+        let mut authenticator = Authenticator::new(Mechanism::XOAuth2, "user@example.com", token);
+        let command = authenticator.initial_command();
+        let response = command.expose().strip_prefix("AUTH XOAUTH2 ").unwrap();
+        assert_eq!(
+            b64.decode(response).unwrap(),
+            b"user=user@example.com\x01auth=Bearer token\x01\x01"
+        );
+    }
+
+    #[test]
+    fn select_preference_and_fallback() {
+        let all = names(&["LOGIN", "PLAIN", "CRAM-MD5", "XOAUTH2", "OAUTHBEARER"]);
+        let select = |advertised: &[String], encrypted, oauth| {
+            Mechanism::select(advertised, None, encrypted, oauth)
+        };
+        assert_eq!(select(&all, true, false), Some(Mechanism::Plain));
+        // without TLS the password is not sent if CRAM-MD5 is available
+        assert_eq!(select(&all, false, false), Some(Mechanism::CramMd5));
+        assert_eq!(select(&all, true, true), Some(Mechanism::OAuthBearer));
+        assert_eq!(
+            select(&names(&["login", "xoauth2"]), true, false),
+            Some(Mechanism::Login)
+        );
+        assert_eq!(
+            select(&names(&["PLAIN", "XOAUTH2"]), true, true),
+            Some(Mechanism::XOAuth2)
+        );
+        assert_eq!(select(&names(&["XOAUTH2"]), true, false), None);
+        assert_eq!(select(&names(&["GSSAPI"]), true, false), None);
+        // a forced mechanism is used even when not advertised
+        assert_eq!(
+            Mechanism::select(&names(&["PLAIN"]), Some(Mechanism::Login), true, false),
+            Some(Mechanism::Login)
+        );
+    }
+}
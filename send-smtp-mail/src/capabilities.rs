@@ -0,0 +1,70 @@
+use crate::reply::Reply;
+
+/// SMTP service extensions advertised in the 250 reply to EHLO (RFC 5321 section 4.1.1.1).
+///
+/// The first reply line is the server greeting, every following line is one
+/// extension keyword optionally followed by parameters.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ServerCapabilities {
+    pub greeting: String,
+    pub starttls: bool,              // RFC 3207
+    pub auth: Vec<String>,           // RFC 4954 SASL mechanisms, upper case
+    pub size: Option<u64>,           // RFC 1870 max message size, Some(0) = no fixed limit
+    pub eight_bit_mime: bool,        // RFC 6152
+    pub smtputf8: bool,              // RFC 6531
+    pub pipelining: bool,            // RFC 2920
+    pub chunking: bool,              // RFC 3030 BDAT
+    pub dsn: bool,                   // RFC 3461
+    pub enhanced_status_codes: bool, // RFC 2034
+    pub requiretls: bool,            // RFC 8689
+    pub unknown: Vec<String>,        // any other keyword line, kept verbatim
+}
+
+impl ServerCapabilities {
+    pub fn from_ehlo(reply: &Reply) -> Self {
+        let mut capabilities = ServerCapabilities {
+            greeting: reply.lines.first().cloned().unwrap_or_default(),
+            ..Default::default()
+        };
+        for line in reply.lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            let keyword = keyword.to_ascii_uppercase();
+            match keyword.as_str() {
+                "STARTTLS" => capabilities.starttls = true,
+                // Old servers advertise "AUTH=LOGIN PLAIN" in addition to "AUTH LOGIN PLAIN"
+                "AUTH" => capabilities.add_auth(words),
+                k if k.starts_with("AUTH=") => {
+                    capabilities.add_auth(std::iter::once(&k[5..]).chain(words))
+                }
+                "SIZE" => {
+                    capabilities.size = Some(words.next().and_then(|s| s.parse().ok()).unwrap_or(0))
+                }
+                "8BITMIME" => capabilities.eight_bit_mime = true,
+                "SMTPUTF8" => capabilities.smtputf8 = true,
+                "PIPELINING" => capabilities.pipelining = true,
+                "CHUNKING" => capabilities.chunking = true,
+                "DSN" => capabilities.dsn = true,
+                "ENHANCEDSTATUSCODES" => capabilities.enhanced_status_codes = true,
+                "REQUIRETLS" => capabilities.requiretls = true,
+                _ => capabilities.unknown.push(line.to_string()),
+            }
+        }
+        capabilities
+    }
+
+    fn add_auth<'a>(&mut self, mechanisms: impl Iterator<Item = &'a str>) {
+        for mechanism in mechanisms {
+            let mechanism = mechanism.to_ascii_uppercase();
+            if !self.auth.contains(&mechanism) {
+                self.auth.push(mechanism);
+            }
+        }
+    }
+
+    pub fn supports_auth(&self, mechanism: &str) -> bool {
+        self.auth.iter().any(|m| m.eq_ignore_ascii_case(mechanism))
+    }
+}
@@ -0,0 +1,183 @@
+pub mod sendmail;
+
+use crate::config::Config;
+use clap::Parser;
+use std::io;
+use std::path::PathBuf;
+
+/// Send an email through an SMTP server.
+///
+/// Every flag has an environment variable counterpart (shown in brackets), flags
+/// override environment variables, which override the .env file in the current
+/// directory. Settings without a flag, e.g. TLS trust or OAuth, are only read
+/// from the environment.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about)]
+pub struct Cli {
+    /// Server profile from the config file, flags override its settings [smtp_profile]
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Config file with the profiles, default ~/.config/send-smtp-mail/config.toml
+    /// [smtp_config_file]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// SMTP server, host or host:port [smtp_server]
+    #[arg(long, value_name = "HOST")]
+    pub server: Option<String>,
+
+    /// Server port, default 587 or 465 with --tls tls [smtp_port]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Sender, a display name may be given: "Ops Bot <ops@example.com>" [smtp_from]
+    #[arg(long, value_name = "ADDRESS")]
+    pub from: Option<String>,
+
+    /// Recipient, repeat for several [smtp_to]
+    #[arg(long, value_name = "ADDRESS")]
+    pub to: Vec<String>,
+
+    /// Subject line [smtp_subject]
+    #[arg(long)]
+    pub subject: Option<String>,
+
+    /// Plain text body [smtp_text]
+    #[arg(long, value_name = "TEXT", conflicts_with = "body_file")]
+    pub body: Option<String>,
+
+    /// Read the plain text body from a file, - for stdin [smtp_text_path]
+    #[arg(long, value_name = "PATH")]
+    pub body_file: Option<PathBuf>,
+
+    /// Send a complete RFC 5322 message (.eml) as is, - for stdin [smtp_eml_path]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["body", "body_file", "subject", "attach"])]
+    pub eml: Option<PathBuf>,
+
+    /// Take the recipients from the To, Cc and Bcc headers of --eml, Bcc is
+    /// removed before sending [smtp_extract_recipients]
+    #[arg(short = 't', long, requires = "eml")]
+    pub extract_recipients: bool,
+
+    /// Attach a file, repeat for several [smtp_attachment_path]
+    #[arg(long, value_name = "PATH")]
+    pub attach: Vec<PathBuf>,
+
+    /// Connection security: starttls, tls (implicit, SMTPS) or plain [smtp_security]
+    #[arg(long, value_name = "MODE", value_parser = ["starttls", "tls", "plain"])]
+    pub tls: Option<String>,
+
+    /// AUTH mechanism, default picks from the server list [smtp_auth_mechanism]
+    #[arg(long, value_name = "MECHANISM", ignore_case = true,
+          value_parser = ["PLAIN", "LOGIN", "CRAM-MD5", "XOAUTH2", "OAUTHBEARER"])]
+    pub auth: Option<String>,
+
+    /// Build the message and write it to stdout instead of sending it
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Debug logging
+    #[arg(short, long)]
+    pub verbose: bool,
+}
+
+impl Cli {
+    /// Settings from the environment and .env file, then the profile, with the
+    /// flags applied on top
+    pub fn config(&self) -> io::Result<Config> {
+        self.apply(Config::from_env())
+    }
+
+    fn apply(&self, mut config: Config) -> io::Result<Config> {
+        if let Some(path) = path_string(&self.config) {
+            config.set("smtp_config_file", &path);
+        }
+        if let Some(profile) = self.profile.clone().or_else(|| config.var("smtp_profile")) {
+            config.load_profile(&profile)?;
+        }
+        // a port in --server host:port beats smtp_port from the environment or profile
+        if self.port.is_none() && self.server.as_ref().is_some_and(|s| s.contains(':')) {
+            config.unset("smtp_port");
+        }
+        let values = [
+            ("smtp_server", self.server.clone()),
+            ("smtp_port", self.port.map(|p| p.to_string())),
+            ("smtp_from", self.from.clone()),
+            ("smtp_subject", self.subject.clone()),
+            ("smtp_text", self.body.clone()),
+            ("smtp_text_path", path_string(&self.body_file)),
+            ("smtp_eml_path", path_string(&self.eml)),
+            ("smtp_security", self.tls.clone()),
+            ("smtp_auth_mechanism", self.auth.clone()),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value {
+                config.set(key, &value);
+            }
+        }
+        // --body-file wins over smtp_text from the environment
+        if self.body_file.is_some() {
+            config.unset("smtp_text");
+        }
+        if self.extract_recipients {
+            // only the recipients given here or in the message, never smtp_to from .env
+            config.set("smtp_extract_recipients", "true");
+            config.set_list("smtp_to", self.to.clone());
+            config.unset("smtp_cc");
+            config.unset("smtp_bcc");
+        } else if !self.to.is_empty() {
+            config.set_list("smtp_to", self.to.clone());
+        }
+        if !self.attach.is_empty() {
+            let paths = self.attach.iter().map(|p| p.display().to_string());
+            config.set_list("smtp_attachment_path", paths.collect());
+        }
+        Ok(config)
+    }
+}
+
+fn path_string(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|p| p.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the flags applied on top of `env`, which stands in for the environment and .env
+    fn apply(env: &[(&str, &str)], args: &[&str]) -> Config {
+        let mut config = Config::default();
+        for (key, value) in env {
+            config.set(key, value);
+        }
+        let cli = Cli::try_parse_from([&["send-smtp-mail"], args].concat()).unwrap();
+        cli.apply(config).unwrap()
+    }
+
+    #[test]
+    fn extract_recipients_ignores_env_recipients() {
+        let env = [
+            ("smtp_to", "default@example.com"),
+            ("smtp_cc", "cc@example.com"),
+            ("smtp_bcc", "bcc@example.com"),
+        ];
+        let config = apply(&env, &["--eml", "m.eml", "-t"]);
+        assert!(config.list("smtp_to").is_empty());
+        assert_eq!(config.var("smtp_cc"), None);
+        assert_eq!(config.var("smtp_bcc"), None);
+        let config = apply(&env, &["--eml", "m.eml", "-t", "--to", "x@example.com"]);
+        assert_eq!(config.list("smtp_to"), ["x@example.com"]);
+    }
+
+    #[test]
+    fn server_port_beats_env_port() {
+        let env = [("smtp_port", "2626")];
+        let config = apply(&env, &["--server", "127.0.0.1:2525"]);
+        assert_eq!(config.var("smtp_port"), None);
+        let config = apply(&env, &["--server", "127.0.0.1"]);
+        assert_eq!(config.var("smtp_port").unwrap(), "2626");
+        let config = apply(&env, &["--server", "127.0.0.1:2525", "--port", "25"]);
+        assert_eq!(config.var("smtp_port").unwrap(), "25");
+    }
+}
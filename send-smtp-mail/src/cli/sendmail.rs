@@ -0,0 +1,122 @@
+// Command line of /usr/sbin/sendmail as used by cron, mail(1) and scripts:
+// sendmail [-t] [-i | -oi] [-f sender] [-F full name] [recipient ...]
+// The message is read from stdin and relayed through the configured server.
+// With -bs the client speaks SMTP on stdin and stdout instead, see session.rs.
+// Options that only matter to a local MTA (-o..., -B, -N, -R, -V, -X, -L, -h, -O)
+// are accepted and ignored, modes other than -bm and -bs are refused.
+
+pub mod session;
+
+use crate::config::Config;
+use std::io;
+
+/// sendmail exit code for a command line error
+pub const EX_USAGE: i32 = 64;
+/// sendmail exit code when the server refused the message with a 5xx reply
+pub const EX_UNAVAILABLE: i32 = 69;
+/// sendmail exit code for a failure that may pass on retry, a 4xx reply or no connection
+pub const EX_TEMPFAIL: i32 = 75;
+/// sendmail exit code for an error in the configuration
+pub const EX_CONFIG: i32 = 78;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Sendmail {
+    pub extract_recipients: bool,  // -t, recipients from To, Cc and Bcc
+    pub ignore_dots: bool,         // -i or -oi, a line with a single "." is not the end
+    pub sender: Option<String>,    // -f or -r, envelope sender
+    pub full_name: Option<String>, // -F, display name of the sender
+    pub verbose: bool,             // -v
+    pub smtp_session: bool,        // -bs, SMTP commands on stdin, replies on stdout
+    pub recipients: Vec<String>,
+}
+
+impl Sendmail {
+    /// Parse the arguments after the program name, getopt style so -ti and
+    /// -fsender work as well as -t -i and -f sender
+    pub fn parse(args: impl IntoIterator<Item = String>) -> io::Result<Sendmail> {
+        let mut sendmail = Sendmail::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                sendmail.recipients.extend(args.by_ref());
+                break;
+            }
+            let Some(flags) = arg.strip_prefix('-').filter(|f| !f.is_empty()) else {
+                sendmail.recipients.push(arg);
+                continue;
+            };
+            for (i, flag) in flags.char_indices() {
+                match flag {
+                    't' => sendmail.extract_recipients = true,
+                    'i' => sendmail.ignore_dots = true,
+                    'v' => sendmail.verbose = true,
+                    'm' | 'n' | 'U' => {} // me too, no aliasing, initial submission
+                    'b' | 'f' | 'r' | 'F' | 'o' | 'B' | 'N' | 'R' | 'V' | 'X' | 'L' | 'h' | 'O' => {
+                        // the value is the rest of this argument or the next one
+                        let rest = &flags[i + flag.len_utf8()..];
+                        let value = match rest.is_empty() {
+                            true => args.next().ok_or_else(|| {
+                                usage(&format!("option -{flag} requires a value"))
+                            })?,
+                            false => rest.to_string(),
+                        };
+                        sendmail.option(flag, value)?;
+                        break;
+                    }
+                    _ => return Err(usage(&format!("unknown option -{flag}"))),
+                }
+            }
+        }
+        Ok(sendmail)
+    }
+
+    fn option(&mut self, flag: char, value: String) -> io::Result<()> {
+        match flag {
+            'f' | 'r' => self.sender = Some(value),
+            'F' => self.full_name = Some(value),
+            'o' if value == "i" => self.ignore_dots = true,
+            'b' if value == "m" => {} // deliver mail, the default
+            'b' if value == "s" => self.smtp_session = true,
+            'b' => {
+                return Err(usage(&format!(
+                    "-b{value} is not supported, only -bm and -bs to send messages"
+                )))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Settings from the environment and .env file and the smtp_profile profile,
+    /// the message from stdin, with -bs from the session for each message
+    pub fn config(&self) -> io::Result<Config> {
+        let mut config = Config::from_env();
+        if let Some(profile) = config.var("smtp_profile") {
+            config.load_profile(&profile)?;
+        }
+        config.set("smtp_eml_path", "-");
+        config.set("smtp_eml_complete_headers", "true");
+        // a session message ends at its "." line already and has RCPT recipients
+        if !self.ignore_dots && !self.smtp_session {
+            config.set("smtp_eml_stop_at_dot", "true");
+        }
+        if self.extract_recipients && !self.smtp_session {
+            config.set("smtp_extract_recipients", "true");
+        }
+        if let Some(sender) = &self.sender {
+            config.set("smtp_from", sender);
+        }
+        if let Some(full_name) = &self.full_name {
+            config.set("smtp_from_name", full_name);
+        }
+        // only the recipients given here or in the message, never smtp_to from .env
+        config.set_list("smtp_to", self.recipients.clone());
+        config.unset("smtp_cc");
+        config.unset("smtp_bcc");
+        Ok(config)
+    }
+}
+
+fn usage(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
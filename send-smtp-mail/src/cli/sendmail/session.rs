@@ -0,0 +1,272 @@
+// sendmail -bs: an SMTP session on stdin and stdout, as PHP mail libraries and
+// Symfony Mailer use it. Each message is relayed through the configured server
+// once its data is complete, the reply to the end of data tells how that went.
+// Recipients are only checked for their syntax here, one refused by the server
+// while others are accepted is logged and the message still reported as sent.
+
+use crate::address::Mailbox;
+use crate::config::Config;
+use crate::state_machine::{State, StateMachine};
+use std::io::{self, BufRead, Write};
+use std::mem;
+
+const HOSTNAME: &str = "localhost";
+
+/// A message received in the session
+#[derive(Debug, Default, PartialEq)]
+pub struct Envelope {
+    pub sender: String, // empty for the null reverse-path <>
+    pub recipients: Vec<String>,
+    pub data: Vec<u8>, // dot-stuffing removed, line endings as sent
+}
+
+/// What to do after a line from the client
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Reply(String),
+    Relay(Envelope), // end of data, the reply depends on the relay
+    Continue,        // a line of the message, no reply
+    Quit(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Session {
+    greeted: bool,
+    sender: Option<String>,
+    recipients: Vec<String>,
+    data: Option<Vec<u8>>, // between the 354 reply and the "." line
+}
+
+impl Session {
+    pub fn greeting() -> String {
+        format!("220 {HOSTNAME} send-smtp-mail ESMTP ready")
+    }
+
+    /// One line from the client including its line ending
+    pub fn line(&mut self, line: &[u8]) -> Step {
+        if let Some(data) = self.data.as_mut() {
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            if text.strip_suffix(b"\r").unwrap_or(text) == b"." {
+                return Step::Relay(Envelope {
+                    sender: self.sender.take().unwrap_or_default(),
+                    recipients: mem::take(&mut self.recipients),
+                    data: self.data.take().unwrap_or_default(),
+                });
+            }
+            // RFC 5321 4.5.2: the client doubled a leading dot
+            data.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+            return Step::Continue;
+        }
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+        let reply = match verb.to_ascii_uppercase().as_str() {
+            "EHLO" => {
+                self.greeted = true;
+                self.reset();
+                format!("250-{HOSTNAME}\r\n250-8BITMIME\r\n250-SMTPUTF8\r\n250 ENHANCEDSTATUSCODES")
+            }
+            "HELO" => {
+                self.greeted = true;
+                self.reset();
+                format!("250 {HOSTNAME}")
+            }
+            "MAIL" => self.mail(args),
+            "RCPT" => self.rcpt(args),
+            "DATA" if self.recipients.is_empty() => "503 5.5.1 Send RCPT first".to_string(),
+            "DATA" => {
+                self.data = Some(Vec::new());
+                "354 End data with <CR><LF>.<CR><LF>".to_string()
+            }
+            "RSET" => {
+                self.reset();
+                "250 2.0.0 OK".to_string()
+            }
+            "NOOP" => "250 2.0.0 OK".to_string(),
+            "VRFY" => "252 2.5.0 Cannot VRFY user, but will accept message".to_string(),
+            "QUIT" => return Step::Quit("221 2.0.0 Bye".to_string()),
+            "" => "500 5.5.2 Syntax error".to_string(),
+            _ => "502 5.5.1 Command not implemented".to_string(),
+        };
+        Step::Reply(reply)
+    }
+
+    fn mail(&mut self, args: &str) -> String {
+        if !self.greeted {
+            return "503 5.5.1 Send EHLO or HELO first".to_string();
+        }
+        if self.sender.is_some() {
+            return "503 5.5.1 Sender already given".to_string();
+        }
+        match path(args, "FROM:") {
+            None => "501 5.5.4 Syntax: MAIL FROM:<address>".to_string(),
+            Some(address) if !address.is_empty() && Mailbox::parse(address).is_err() => {
+                "553 5.1.7 Invalid sender address".to_string()
+            }
+            Some(address) => {
+                self.sender = Some(address.to_string());
+                "250 2.1.0 Sender OK".to_string()
+            }
+        }
+    }
+
+    fn rcpt(&mut self, args: &str) -> String {
+        if self.sender.is_none() {
+            return "503 5.5.1 Send MAIL first".to_string();
+        }
+        match path(args, "TO:") {
+            None | Some("") => "501 5.5.4 Syntax: RCPT TO:<address>".to_string(),
+            Some(address) if Mailbox::parse(address).is_err() => {
+                "553 5.1.3 Invalid recipient address".to_string()
+            }
+            Some(address) => {
+                self.recipients.push(address.to_string());
+                "250 2.1.5 Recipient OK".to_string()
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sender = None;
+        self.recipients.clear();
+        self.data = None;
+    }
+}
+
+// "FROM:<a@example.com> SIZE=10" -> "a@example.com", parameters are ignored
+fn path<'a>(args: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = args.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = args[prefix.len()..].trim_start().strip_prefix('<')?;
+    Some(&rest[..rest.find('>')?])
+}
+
+/// Answer the session on stdin and stdout, relaying each message with `config`
+pub async fn serve(config: &Config) -> io::Result<()> {
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    write_reply(&mut output, &Session::greeting())?;
+    let mut session = Session::default();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(()); // the client went away without QUIT
+        }
+        let reply = match session.line(&line) {
+            Step::Continue => continue,
+            Step::Reply(reply) => reply,
+            Step::Relay(envelope) => relay(config, envelope).await,
+            Step::Quit(reply) => return write_reply(&mut output, &reply),
+        };
+        write_reply(&mut output, &reply)?;
+    }
+}
+
+fn write_reply(output: &mut impl Write, reply: &str) -> io::Result<()> {
+    output.write_all(reply.as_bytes())?;
+    output.write_all(b"\r\n")?;
+    output.flush()
+}
+
+// send the message through the configured server, the reply to the end of data
+async fn relay(config: &Config, envelope: Envelope) -> String {
+    let mut config = config.clone();
+    // the null sender <> keeps -f, smtp_from or the From header
+    if !envelope.sender.is_empty() {
+        config.set("smtp_from", &envelope.sender);
+    }
+    config.set_list("smtp_to", envelope.recipients);
+    config.set_input(envelope.data);
+    let mut state_machine = match StateMachine::new_from_config(&config) {
+        Ok(state_machine) => state_machine,
+        Err(e) => {
+            log::error!("{}", e);
+            return format!("451 4.3.5 {e}");
+        }
+    };
+    state_machine.run().await;
+    match (&state_machine.state, state_machine.error_reply()) {
+        (State::Finished, _) => "250 2.0.0 Message accepted for delivery".to_string(),
+        (_, Some(reply)) if reply.code >= 500 => {
+            format!("554 5.0.0 Relay refused the message: {reply}")
+        }
+        (_, Some(reply)) => format!("451 4.0.0 Relay deferred the message: {reply}"),
+        (_, None) => format!(
+            "451 4.4.1 Relay through {} failed, try again later",
+            state_machine.smtp_connection.host
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(session: &mut Session, line: &str) -> String {
+        match session.line(line.as_bytes()) {
+            Step::Reply(reply) | Step::Quit(reply) => reply,
+            step => panic!("expected a reply to {line:?}, got {step:?}"),
+        }
+    }
+
+    fn code(session: &mut Session, line: &str) -> String {
+        reply(session, line)[..3].to_string()
+    }
+
+    #[test]
+    fn transaction() {
+        let mut session = Session::default();
+        assert!(reply(&mut session, "EHLO client\r\n").ends_with("250 ENHANCEDSTATUSCODES"));
+        assert_eq!(
+            code(&mut session, "MAIL FROM:<a@example.com> SIZE=20\r\n"),
+            "250"
+        );
+        assert_eq!(code(&mut session, "rcpt to:<b@example.com>\r\n"), "250");
+        assert_eq!(code(&mut session, "RCPT TO: <c@example.com>\r\n"), "250");
+        assert_eq!(code(&mut session, "DATA\r\n"), "354");
+        for line in ["Subject: hi\r\n", "\r\n", "..leading dot\r\n", "end\n"] {
+            assert_eq!(session.line(line.as_bytes()), Step::Continue);
+        }
+        assert_eq!(
+            session.line(b".\r\n"),
+            Step::Relay(Envelope {
+                sender: "a@example.com".to_string(),
+                recipients: vec!["b@example.com".to_string(), "c@example.com".to_string()],
+                data: b"Subject: hi\r\n\r\n.leading dot\r\nend\n".to_vec(),
+            })
+        );
+        // the next transaction starts empty
+        assert_eq!(code(&mut session, "RCPT TO:<b@example.com>\r\n"), "503");
+        assert_eq!(code(&mut session, "QUIT\r\n"), "221");
+    }
+
+    #[test]
+    fn commands_out_of_order() {
+        let mut session = Session::default();
+        assert_eq!(code(&mut session, "MAIL FROM:<a@example.com>\r\n"), "503");
+        assert_eq!(code(&mut session, "HELO client\r\n"), "250");
+        assert_eq!(code(&mut session, "DATA\r\n"), "503");
+        assert_eq!(code(&mut session, "MAIL FROM:<>\r\n"), "250");
+        assert_eq!(code(&mut session, "MAIL FROM:<a@example.com>\r\n"), "503");
+        assert_eq!(code(&mut session, "DATA\r\n"), "503");
+        assert_eq!(code(&mut session, "RSET\r\n"), "250");
+        assert_eq!(code(&mut session, "RCPT TO:<b@example.com>\r\n"), "503");
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let mut session = Session::default();
+        assert_eq!(code(&mut session, "EHLO client\r\n"), "250");
+        assert_eq!(code(&mut session, "MAIL a@example.com\r\n"), "501");
+        assert_eq!(code(&mut session, "MAIL FROM:<not an address>\r\n"), "553");
+        assert_eq!(code(&mut session, "MAIL FROM:<a@example.com>\r\n"), "250");
+        assert_eq!(code(&mut session, "RCPT TO:<>\r\n"), "501");
+        assert_eq!(code(&mut session, "VRFY b\r\n"), "252");
+        assert_eq!(code(&mut session, "NOOP\r\n"), "250");
+        assert_eq!(code(&mut session, "ETRN example.com\r\n"), "502");
+        assert_eq!(code(&mut session, "\r\n"), "500");
+    }
+}
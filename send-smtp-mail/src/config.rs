@@ -0,0 +1,266 @@
+pub mod profile;
+
+use crate::address::{self, Mailbox};
+use dotenv::dotenv;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Settings looked up by their .env key, e.g. `smtp_server`. Values set here,
+/// from command line flags, override environment variables, which override the
+/// .env file as dotenv never replaces a variable that is already set.
+/// Errors name the setting, or the profile key it came from.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    overrides: HashMap<String, Option<Vec<String>>>, // None hides the environment value
+    sources: HashMap<String, String>,                // e.g. "config.toml: profiles.gmail.port"
+    input: Option<Vec<u8>>,                          // read for "-" instead of stdin
+}
+
+impl Config {
+    /// Environment variables, with the .env file in the current directory loaded
+    pub fn from_env() -> Config {
+        dotenv().ok();
+        Config::default()
+    }
+
+    /// Apply a named profile from smtp_config_file, default
+    /// ~/.config/send-smtp-mail/config.toml, on top of the environment
+    pub fn load_profile(&mut self, name: &str) -> io::Result<()> {
+        let path = match self.var("smtp_config_file") {
+            Some(path) => PathBuf::from(path),
+            None => profile::default_path(),
+        };
+        let settings = profile::load(&path, name)?;
+        // a profile server without port must not get the port from .env
+        let has = |key: &str| settings.iter().any(|(k, _)| k == key);
+        if has("smtp_server") && !has("smtp_port") {
+            self.unset("smtp_port");
+        }
+        // a secret source in the profile replaces any source from .env,
+        // matched by suffix as smtp_oauth_token is a prefix of smtp_oauth_token_url
+        const SUFFIXES: [&str; 4] = ["", "_file", "_command", "_keyring"];
+        for secret in [
+            "smtp_password",
+            "smtp_oauth_refresh_token",
+            "smtp_oauth_client_secret",
+            "smtp_oauth_token",
+        ] {
+            let is_source = |key: &str| {
+                key.strip_prefix(secret)
+                    .is_some_and(|suffix| SUFFIXES.contains(&suffix))
+            };
+            if settings.iter().any(|(k, _)| is_source(k)) {
+                for suffix in SUFFIXES {
+                    self.unset(&format!("{secret}{suffix}"));
+                }
+            }
+        }
+        for (key, value) in settings {
+            self.set(&key, &value);
+            let profile_key = key.strip_prefix("smtp_").unwrap_or(&key);
+            let source = format!("{}: profiles.{name}.{profile_key}", path.display());
+            self.sources.insert(key, source);
+        }
+        Ok(())
+    }
+
+    /// The setting as named in errors, the profile key when it came from a profile
+    pub fn name(&self, key: &str) -> String {
+        self.sources
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    /// Error for a missing or invalid setting, e.g. "smtp_port: not set"
+    pub fn error(&self, key: &str, message: impl fmt::Display) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: {message}", self.name(key)),
+        )
+    }
+
+    /// A setting that must be set
+    pub fn required(&self, key: &str) -> io::Result<String> {
+        self.var(key).ok_or_else(|| self.error(key, "not set"))
+    }
+
+    /// Parse a setting with e.g. `TlsPolicy::from_name`, None when not set
+    pub fn parse<T>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Option<T>,
+        expected: &str,
+    ) -> io::Result<Option<T>> {
+        match self.var(key) {
+            None => Ok(None),
+            Some(value) => match parse(&value) {
+                Some(parsed) => Ok(Some(parsed)),
+                None => {
+                    Err(self.error(key, format!("invalid value {value:?}, expected {expected}")))
+                }
+            },
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.sources.remove(key);
+        self.overrides
+            .insert(key.to_string(), Some(vec![value.to_string()]));
+    }
+
+    /// Replace a list setting, e.g. smtp_to from repeated --to flags
+    pub fn set_list(&mut self, key: &str, values: Vec<String>) {
+        self.sources.remove(key);
+        self.overrides.insert(key.to_string(), Some(values));
+    }
+
+    /// Treat a setting as not set even if it is in the environment
+    pub fn unset(&mut self, key: &str) {
+        self.sources.remove(key);
+        self.overrides.insert(key.to_string(), None);
+    }
+
+    /// Data a "-" path reads instead of stdin, e.g. a message received in a
+    /// sendmail -bs session where stdin carries the SMTP commands
+    pub fn set_input(&mut self, data: Vec<u8>) {
+        self.input = Some(data);
+    }
+
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    pub fn var(&self, key: &str) -> Option<String> {
+        match self.overrides.get(key) {
+            Some(values) => values.as_ref().map(|v| v.join(",")),
+            None => env::var(key).ok(),
+        }
+    }
+
+    /// Comma separated list, empty when not set
+    pub fn list(&self, key: &str) -> Vec<String> {
+        if let Some(values) = self.overrides.get(key) {
+            return values.clone().unwrap_or_default();
+        }
+        self.var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    pub fn bool(&self, key: &str, default: bool) -> io::Result<bool> {
+        let value = self.parse(
+            key,
+            |value| match value {
+                "true" | "True" | "TRUE" | "1" => Some(true),
+                "false" | "False" | "FALSE" | "0" => Some(false),
+                _ => None,
+            },
+            "true or false",
+        )?;
+        Ok(value.unwrap_or(default))
+    }
+
+    /// Comma separated list of mailboxes, empty when not set
+    pub fn mailboxes(&self, key: &str) -> io::Result<Vec<Mailbox>> {
+        address::parse_list(&self.var(key).unwrap_or_default()).map_err(|e| self.error(key, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_machine::StateMachine;
+    use std::fs;
+
+    // a config.toml in the temp directory, named per test as tests run in parallel
+    fn with_profile(test: &str, profile: &str) -> Config {
+        let mut config = Config::default();
+        load_profile(&mut config, test, profile);
+        config
+    }
+
+    fn load_profile(config: &mut Config, test: &str, profile: &str) {
+        let path = env::temp_dir().join(format!("send-smtp-mail-{test}.toml"));
+        fs::write(&path, format!("[profiles.a]\n{profile}")).unwrap();
+        config.set("smtp_config_file", path.to_str().unwrap());
+        config.load_profile("a").unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_setting() {
+        let mut config = Config::default();
+        config.set("smtp_chunking", "yes");
+        let e = config.bool("smtp_chunking", true).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "smtp_chunking: invalid value \"yes\", expected true or false"
+        );
+        config.unset("smtp_server");
+        let e = config.required("smtp_server").unwrap_err();
+        assert_eq!(e.to_string(), "smtp_server: not set");
+    }
+
+    #[test]
+    fn errors_name_the_profile_key() {
+        let config = with_profile(
+            "errors_name_the_profile_key",
+            "server = \"localhost\"\nsecurity = \"plain\"\ntls_policy = \"required\"\n",
+        );
+        let Err(e) = StateMachine::new_from_config(&config) else {
+            panic!("required TLS with plain security must be rejected");
+        };
+        assert!(
+            e.to_string()
+                .contains(": profiles.a.tls_policy: required conflicts"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn profile_secret_replaces_env_sources() {
+        let mut config = Config::default();
+        config.set("smtp_oauth_token", "from-env");
+        config.set("smtp_oauth_client_secret_command", "pass show smtp");
+        load_profile(
+            &mut config,
+            "profile_secret_replaces_env_sources",
+            "oauth_token_file = \"/run/token\"\noauth_client_secret_keyring = \"service smtp\"\n",
+        );
+        assert_eq!(config.var("smtp_oauth_token"), None);
+        assert_eq!(config.var("smtp_oauth_token_file").unwrap(), "/run/token");
+        assert_eq!(config.var("smtp_oauth_client_secret_command"), None);
+        assert_eq!(
+            config.var("smtp_oauth_client_secret_keyring").unwrap(),
+            "service smtp"
+        );
+    }
+
+    #[test]
+    fn profile_token_url_keeps_env_token() {
+        let mut config = Config::default();
+        config.set("smtp_oauth_token", "from-env");
+        load_profile(
+            &mut config,
+            "profile_token_url_keeps_env_token",
+            "oauth_token_url = \"https://example.com/token\"\n",
+        );
+        // smtp_oauth_token_url only shares the prefix, it is not a token source
+        assert_eq!(config.var("smtp_oauth_token").unwrap(), "from-env");
+    }
+
+    #[test]
+    fn set_replaces_the_profile_source() {
+        let mut config = with_profile("set_replaces_the_profile_source", "port = 2525\n");
+        assert!(config.name("smtp_port").ends_with(": profiles.a.port"));
+        config.set("smtp_port", "25");
+        assert_eq!(config.name("smtp_port"), "smtp_port");
+    }
+}
@@ -0,0 +1,150 @@
+// Named server profiles in ~/.config/send-smtp-mail/config.toml:
+//
+// [profiles.office365]
+// server = "smtp.office365.com"
+// port = 587
+// security = "starttls"
+// auth_mechanism = "XOAUTH2"
+// from = "Ops Bot <ops@example.com>"
+//
+// Each key is the .env key without the smtp_ prefix. Values are checked when the
+// profile is loaded, errors name the file and key, e.g. profiles.gmail.port.
+
+use crate::address::Mailbox;
+use crate::auth::Mechanism;
+use crate::stream::{ConnectionSecurity, TlsPolicy};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Port,
+    Bool,
+    Security,
+    TlsPolicy,
+    Mechanism,
+    Mailbox,
+}
+
+// keys a profile may set, everything about the server and the sender,
+// secrets only as a file, command or keyring lookup, never the value itself
+const KEYS: [(&str, Kind); 29] = [
+    ("server", Kind::Text),
+    ("port", Kind::Port),
+    ("security", Kind::Security),
+    ("tls_policy", Kind::TlsPolicy),
+    ("tls_ca_file", Kind::Text),
+    ("tls_system_roots", Kind::Bool),
+    ("tls_client_cert", Kind::Text),
+    ("tls_client_key", Kind::Text),
+    ("allow_plaintext_auth", Kind::Bool),
+    ("auth_mechanism", Kind::Mechanism),
+    ("username", Kind::Text),
+    ("password_file", Kind::Text),
+    ("password_command", Kind::Text),
+    ("password_keyring", Kind::Text),
+    ("oauth_token_url", Kind::Text),
+    ("oauth_client_id", Kind::Text),
+    ("oauth_scope", Kind::Text),
+    ("oauth_client_secret_file", Kind::Text),
+    ("oauth_client_secret_command", Kind::Text),
+    ("oauth_client_secret_keyring", Kind::Text),
+    ("oauth_token_file", Kind::Text),
+    ("oauth_token_command", Kind::Text),
+    ("oauth_token_keyring", Kind::Text),
+    ("oauth_refresh_token_file", Kind::Text),
+    ("oauth_refresh_token_command", Kind::Text),
+    ("oauth_refresh_token_keyring", Kind::Text),
+    ("from", Kind::Mailbox),
+    ("from_name", Kind::Text),
+    ("reply_to", Kind::Mailbox),
+];
+
+/// $XDG_CONFIG_HOME/send-smtp-mail/config.toml, by default in ~/.config
+pub fn default_path() -> PathBuf {
+    let base = match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) => Path::new(&home).join(".config"),
+        _ => PathBuf::from(".config"),
+    };
+    base.join("send-smtp-mail").join("config.toml")
+}
+
+/// The settings of a profile as .env keys and values
+pub fn load(path: &Path, name: &str) -> io::Result<Vec<(String, String)>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    parse(&text, name).map_err(|e| invalid(format!("{}: {e}", path.display())))
+}
+
+fn parse(text: &str, name: &str) -> Result<Vec<(String, String)>, String> {
+    let mut file: Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    let Some(profiles) = file.remove("profiles") else {
+        return Err("no [profiles] table".to_string());
+    };
+    let Value::Table(mut profiles) = profiles else {
+        return Err("profiles: expected a table of profiles".to_string());
+    };
+    let available = profiles.keys().cloned().collect::<Vec<_>>().join(", ");
+    let Some(profile) = profiles.remove(name) else {
+        return Err(format!("no profile {name}, available: {available}"));
+    };
+    let Value::Table(profile) = profile else {
+        return Err(format!(
+            "profiles.{name}: expected a table, e.g. [profiles.{name}]"
+        ));
+    };
+    let mut settings = Vec::new();
+    for (key, value) in profile {
+        let Some((_, kind)) = KEYS.iter().find(|(k, _)| *k == key) else {
+            return Err(format!("profiles.{name}.{key}: unknown key"));
+        };
+        let value = check(*kind, &value).map_err(|e| format!("profiles.{name}.{key}: {e}"))?;
+        settings.push((format!("smtp_{key}"), value));
+    }
+    Ok(settings)
+}
+
+// the value as the string a .env file would hold
+fn check(kind: Kind, value: &Value) -> Result<String, String> {
+    match (kind, value) {
+        (Kind::Port, Value::Integer(port)) if (1..=65535).contains(port) => Ok(port.to_string()),
+        (Kind::Port, _) => Err(format!("expected a port number 1-65535, got {value}")),
+        (Kind::Bool, Value::Boolean(b)) => Ok(b.to_string()),
+        (Kind::Bool, _) => Err(format!("expected true or false, got {value}")),
+        (_, Value::String(s)) => {
+            let valid = match kind {
+                Kind::Security => ConnectionSecurity::from_name(s).is_some(),
+                Kind::TlsPolicy => TlsPolicy::from_name(s).is_some(),
+                Kind::Mechanism => Mechanism::from_name(s).is_some(),
+                Kind::Mailbox => {
+                    Mailbox::parse(s).map_err(|e| e.to_string())?;
+                    true
+                }
+                _ => true,
+            };
+            match valid {
+                true => Ok(s.clone()),
+                false => Err(format!("expected {}, got {s:?}", expected(kind))),
+            }
+        }
+        _ => Err(format!("expected a string, got {value}")),
+    }
+}
+
+fn expected(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Security => "starttls, tls or plain",
+        Kind::TlsPolicy => "required, opportunistic or disabled",
+        Kind::Mechanism => "PLAIN, LOGIN, CRAM-MD5, XOAUTH2 or OAUTHBEARER",
+        _ => "a string",
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,359 @@
+// RFC 5321 section 4.1.1.4 and 4.5.2: the DATA payload is CRLF terminated lines,
+// a line starting with "." gets an extra "." so it is not taken as the end of data.
+// RFC 3030 BDAT sends the message in counted chunks, no dot-stuffing or terminator.
+
+use crate::mime::{Base64Encoder, Segment, FILE_CHUNK_SIZE};
+use std::collections::VecDeque;
+use std::io;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+
+// BDAT chunk size unless smtp_bdat_chunk_size is set
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Incremental encoder for the DATA payload. Chunks may split lines anywhere,
+/// the state carries over so streamed content is stuffed the same as in one piece.
+pub struct DataEncoder {
+    dot_stuffing: bool, // off for BDAT, only line endings are normalised
+    line_start: bool,   // next byte starts a new line
+    pending_cr: bool,   // last chunk ended with CR, LF may follow in the next one
+    line_length: usize,
+    max_line_length: usize, // longest line seen, for diagnostics
+}
+
+impl Default for DataEncoder {
+    fn default() -> Self {
+        DataEncoder::new()
+    }
+}
+
+impl DataEncoder {
+    pub fn new() -> Self {
+        DataEncoder {
+            dot_stuffing: true,
+            line_start: true,
+            pending_cr: false,
+            line_length: 0,
+            max_line_length: 0,
+        }
+    }
+
+    /// For BDAT: convert line endings but leave leading dots, no terminator line
+    pub fn without_dot_stuffing() -> Self {
+        DataEncoder {
+            dot_stuffing: false,
+            ..DataEncoder::new()
+        }
+    }
+
+    /// Dot-stuff and convert bare CR or LF to CRLF
+    pub fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(chunk.len() + chunk.len() / 64 + 2);
+        for &b in chunk {
+            if self.pending_cr {
+                self.pending_cr = false;
+                self.end_line(&mut out);
+                if b == b'\n' {
+                    continue; // CRLF, already written
+                }
+            }
+            match b {
+                b'\r' => self.pending_cr = true,
+                b'\n' => self.end_line(&mut out),
+                _ => {
+                    if self.dot_stuffing && self.line_start && b == b'.' {
+                        out.push(b'.');
+                    }
+                    self.line_start = false;
+                    self.line_length += 1;
+                    out.push(b);
+                }
+            }
+        }
+        out
+    }
+
+    /// End of data: complete the last line and add the "." terminator line
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if self.pending_cr || !self.line_start {
+            self.pending_cr = false;
+            self.end_line(&mut out);
+        }
+        if self.dot_stuffing {
+            out.extend_from_slice(b".\r\n");
+        }
+        out
+    }
+
+    /// Longest line written so far excluding CRLF, SMTP servers may reject over 998
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    fn end_line(&mut self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"\r\n");
+        self.max_line_length = self.max_line_length.max(self.line_length);
+        self.line_length = 0;
+        self.line_start = true;
+    }
+}
+
+/// Exact size of the DATA payload for SIZE=, dot-stuffed with CRLF line ends but
+/// without the terminating ".". Files are not read, base64 needs no stuffing.
+pub fn data_size(segments: &[Segment]) -> u64 {
+    let mut encoder = DataEncoder::new();
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Bytes(bytes) => encoder.encode(bytes).len() as u64,
+            Segment::File { .. } => segment.encoded_len(),
+        })
+        .sum()
+}
+
+/// How much of the DATA payload has been written, before dot-stuffing. `total`
+/// is known up front so callers can show a percentage for large attachments.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Progress {
+    pub sent: u64,
+    pub total: u64,
+}
+
+/// Called after each chunk of the message is written to the server
+pub type ProgressCallback = Box<dyn FnMut(&Progress) + Send>;
+
+/// Log every 10% of messages over 1 MiB, smaller ones are sent too quickly to matter
+pub fn log_progress() -> ProgressCallback {
+    let start = std::time::Instant::now();
+    let mut logged = 0;
+    Box::new(move |progress: &Progress| {
+        if progress.total < 1024 * 1024 {
+            return;
+        }
+        let percent = progress.sent * 100 / progress.total;
+        if percent / 10 > logged / 10 {
+            logged = percent;
+            let mb = progress.sent as f64 / (1024.0 * 1024.0);
+            log::info!(
+                "Sent {}% {:.2}Mb {:.2}Mb/sec",
+                percent,
+                mb,
+                mb / start.elapsed().as_secs_f64().max(0.001)
+            );
+        }
+    })
+}
+
+/// Reads a rendered message in bounded chunks, files are opened in turn and
+/// base64 encoded as they are read. Only one chunk is in memory at a time so
+/// the writer waiting on the socket throttles reading from disk.
+pub struct MessageReader {
+    segments: VecDeque<Segment>,
+    file: Option<(tokio::fs::File, Base64Encoder)>,
+}
+
+impl MessageReader {
+    pub fn new(segments: Vec<Segment>) -> Self {
+        MessageReader {
+            segments: segments.into(),
+            file: None,
+        }
+    }
+
+    /// Next piece of the transfer encoded message, None at the end
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some((file, encoder)) = &mut self.file {
+                let mut buffer = vec![0; FILE_CHUNK_SIZE];
+                let n = file.read(&mut buffer).await?;
+                if n > 0 {
+                    return Ok(Some(encoder.encode(&buffer[..n])));
+                }
+                let rest = encoder.finish();
+                self.file = None;
+                if !rest.is_empty() {
+                    return Ok(Some(rest));
+                }
+            }
+            match self.segments.pop_front() {
+                None => return Ok(None),
+                Some(Segment::Bytes(mut bytes)) => {
+                    if bytes.len() > FILE_CHUNK_SIZE {
+                        let rest = bytes.split_off(FILE_CHUNK_SIZE);
+                        self.segments.push_front(Segment::Bytes(rest));
+                    }
+                    return Ok(Some(bytes));
+                }
+                Some(Segment::File { path, .. }) => {
+                    let file = tokio::fs::File::open(&path).await.map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("Failed to read attachment {}: {}", path.display(), e),
+                        )
+                    })?;
+                    self.file = Some((file, Base64Encoder::new()));
+                }
+            }
+        }
+    }
+}
+
+/// Splits the message into BDAT chunks of a fixed size, reading one piece ahead
+/// so the final chunk is known and can be sent as BDAT <n> LAST
+pub struct ChunkReader {
+    reader: MessageReader,
+    encoder: DataEncoder,
+    chunk_size: usize,
+    buffer: Vec<u8>, // at most chunk_size plus one piece from the reader
+    done: bool,
+    progress: Progress,
+    started: Instant,
+}
+
+impl ChunkReader {
+    pub fn new(segments: Vec<Segment>, chunk_size: usize) -> Self {
+        ChunkReader {
+            progress: Progress {
+                sent: 0,
+                total: segments.iter().map(Segment::encoded_len).sum(),
+            },
+            reader: MessageReader::new(segments),
+            encoder: DataEncoder::without_dot_stuffing(),
+            chunk_size: chunk_size.max(1),
+            buffer: Vec::new(),
+            done: false,
+            started: Instant::now(),
+        }
+    }
+
+    /// Next chunk and whether it is the last one
+    pub async fn next_chunk(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        while !self.done && self.buffer.len() <= self.chunk_size {
+            match self.reader.next_chunk().await? {
+                Some(piece) => {
+                    let encoded = self.encoder.encode(&piece);
+                    self.buffer.extend_from_slice(&encoded);
+                }
+                None => {
+                    let rest = self.encoder.finish();
+                    self.buffer.extend_from_slice(&rest);
+                    self.done = true;
+                }
+            }
+        }
+        let n = self.buffer.len().min(self.chunk_size);
+        let chunk: Vec<u8> = self.buffer.drain(..n).collect();
+        self.progress.sent += chunk.len() as u64;
+        self.progress.total = self.progress.total.max(self.progress.sent);
+        Ok((chunk, self.done && self.buffer.is_empty()))
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_chunks(encoder: &mut DataEncoder, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(encoder.encode(chunk));
+        }
+        out.extend(encoder.finish());
+        out
+    }
+
+    #[test]
+    fn leading_dots_are_stuffed() {
+        let out = encode_chunks(
+            &mut DataEncoder::new(),
+            &[b".hidden\r\n..two\r\nmid.dot\r\n"],
+        );
+        assert_eq!(out, b"..hidden\r\n...two\r\nmid.dot\r\n.\r\n");
+    }
+
+    #[test]
+    fn leading_dot_at_chunk_start() {
+        let out = encode_chunks(&mut DataEncoder::new(), &[b"a\r\n", b".b"]);
+        assert_eq!(out, b"a\r\n..b\r\n.\r\n");
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let out = encode_chunks(&mut DataEncoder::new(), &[b"line1\r", b"\n.next\r", b"\n"]);
+        assert_eq!(out, b"line1\r\n..next\r\n.\r\n");
+    }
+
+    #[test]
+    fn cr_at_end_of_data() {
+        let out = encode_chunks(&mut DataEncoder::new(), &[b"last\r"]);
+        assert_eq!(out, b"last\r\n.\r\n");
+    }
+
+    #[test]
+    fn bare_cr_and_lf() {
+        let out = encode_chunks(&mut DataEncoder::new(), &[b"a\rb\nc\r\r\nd"]);
+        assert_eq!(out, b"a\r\nb\r\nc\r\n\r\nd\r\n.\r\n");
+    }
+
+    #[test]
+    fn empty_data() {
+        assert_eq!(encode_chunks(&mut DataEncoder::new(), &[]), b".\r\n");
+    }
+
+    #[test]
+    fn bdat_without_dot_stuffing() {
+        let mut encoder = DataEncoder::without_dot_stuffing();
+        let out = encode_chunks(&mut encoder, &[b".x\n", b".y"]);
+        assert_eq!(out, b".x\r\n.y\r\n");
+    }
+
+    // all chunks of the message, checking that only the last one is flagged
+    async fn read_chunks(data: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut reader = ChunkReader::new(vec![Segment::Bytes(data.to_vec())], chunk_size);
+        let mut chunks = Vec::new();
+        loop {
+            let (chunk, last) = reader.next_chunk().await.unwrap();
+            chunks.push(chunk);
+            if last {
+                return chunks;
+            }
+            assert_eq!(chunks.last().unwrap().len(), chunk_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunk_boundaries() {
+        let chunks = read_chunks(b"0123456789\r\nabc", 5).await;
+        assert_eq!(chunks, [&b"01234"[..], b"56789", b"\r\nabc", b"\r\n"]);
+        // an exact multiple of the chunk size ends with a full last chunk, not an empty one
+        let chunks = read_chunks(b"0123456789\r\n", 4).await;
+        assert_eq!(chunks, [&b"0123"[..], b"4567", b"89\r\n"]);
+        let chunks = read_chunks(b"01234567\r\n", 5).await;
+        assert_eq!(chunks, [&b"01234"[..], b"567\r\n"]);
+        assert_eq!(read_chunks(b"", 5).await, [b""]);
+    }
+
+    #[tokio::test]
+    async fn chunks_across_reader_pieces() {
+        let data: Vec<u8> = b"line of text\r\n".repeat(FILE_CHUNK_SIZE / 7);
+        let chunks = read_chunks(&data, 1000).await;
+        assert_eq!(chunks.len(), data.len().div_ceil(1000));
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn max_line_length() {
+        let mut encoder = DataEncoder::new();
+        encode_chunks(&mut encoder, &[b"12345\n12", b"3456789\n.1\n"]);
+        assert_eq!(encoder.max_line_length(), 9);
+    }
+}
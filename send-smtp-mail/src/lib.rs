@@ -1,5 +1,16 @@
 //use tokio_rustls::client::TlsStream;
+pub mod address;
+pub mod auth;
+pub mod capabilities;
+pub mod cli;
+pub mod config;
+pub mod data;
 pub mod log4; // Makes the module accessible to the main function
+pub mod mime;
+pub mod oauth;
+pub mod reply;
+pub mod secret;
 pub mod state_events;
 pub mod state_machine;
 mod stream;
+pub mod tls;
@@ -4,12 +4,29 @@ use log4rs::config::{Appender, Config, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use std::sync::OnceLock;
 
+/// Log file in the current directory unless logging only to stderr
+pub const LOG_FILE: &str = "my-log.log";
+
 pub fn init_log() {
+    init_log_with_level(LevelFilter::Info);
+}
+
+/// Console output goes to stderr so stdout stays free for --dry-run
+pub fn init_log_with_level(level: LevelFilter) {
+    init_log_to(level, Some(LOG_FILE));
+}
+
+/// As init_log_with_level, with the log file optional. A file that can not be
+/// created, e.g. in a read-only or deleted directory, is reported and skipped.
+pub fn init_log_to(level: LevelFilter, log_file: Option<&str>) {
     static INIT: OnceLock<()> = OnceLock::new(); // initialise only once
     INIT.get_or_init(|| {
-        let logfile = log4rs::append::file::FileAppender::builder()
-            .build("my-log.log")
-            .unwrap();
+        let logfile = log_file.and_then(|path| {
+            log4rs::append::file::FileAppender::builder()
+                .build(path)
+                .map_err(|e| eprintln!("Not logging to {path}: {e}"))
+                .ok()
+        });
 
         // {d}: Timestamp of the log entry.
         // {l}: Log level (e.g., INFO, DEBUG).
@@ -17,25 +34,26 @@ pub fn init_log() {
         // {n}: Newline character.
         // {h}: highlight ??
         let console = console::ConsoleAppender::builder()
+            .target(console::Target::Stderr)
             .encoder(Box::new(PatternEncoder::new(
                 "{t}:: {h({m}{n})}", // Add custom formatting
             )))
             .build();
 
-        let config = Config::builder()
-            .appender(Appender::builder().build("logfile", Box::new(logfile)))
-            .appender(Appender::builder().build("console", Box::new(console)))
+        let mut config =
+            Config::builder().appender(Appender::builder().build("console", Box::new(console)));
+        let mut root = Root::builder().appender("console");
+        if let Some(logfile) = logfile {
+            config = config.appender(Appender::builder().build("logfile", Box::new(logfile)));
+            root = root.appender("logfile");
+        }
+        let config = config
             .logger(
                 Logger::builder()
                     .additive(false)
                     .build("my_module", LevelFilter::Debug), // Set to Debug for detailed logs
             )
-            .build(
-                Root::builder()
-                    .appender("console")
-                    .appender("logfile")
-                    .build(LevelFilter::Info),
-            )
+            .build(root.build(level))
             .unwrap();
 
         log4rs::init_config(config).unwrap();
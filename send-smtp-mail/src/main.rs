@@ -1,8 +1,16 @@
 // use send_smtp_mail::connect_to_server;
+use clap::Parser;
+use send_smtp_mail::cli::sendmail::{self, Sendmail};
+use send_smtp_mail::cli::Cli;
 use send_smtp_mail::log4;
-use send_smtp_mail::state_events;
 use send_smtp_mail::state_machine;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
 use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process;
 
 // use local module smtp_starttls::smtp_starttls;
 // mod smtp_starttls;
@@ -11,61 +19,89 @@ use std::io;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    log4::init_log();
+    // installed or symlinked as sendmail: the classic command line and exit codes
+    let mut args = env::args();
+    let program = args.next().unwrap_or_default();
+    let sendmail_mode = Path::new(&program).file_name() == Some(OsStr::new("sendmail"));
+    // sendmail reports a bad setup as EX_CONFIG, the plain command with 1
+    let (name, config_error) = match sendmail_mode {
+        true => ("sendmail", sendmail::EX_CONFIG),
+        false => ("send-smtp-mail", 1),
+    };
+    let (config, verbose, dry_run, smtp_session, default_level) = if sendmail_mode {
+        let sendmail =
+            Sendmail::parse(args).unwrap_or_else(|e| exit_with(name, sendmail::EX_USAGE, e));
+        let config = sendmail
+            .config()
+            .unwrap_or_else(|e| exit_with(name, config_error, e));
+        // only errors, cron mails anything written to stderr
+        let session = sendmail.smtp_session;
+        (
+            config,
+            sendmail.verbose,
+            false,
+            session,
+            log::LevelFilter::Error,
+        )
+    } else {
+        let cli = Cli::parse();
+        let config = cli
+            .config()
+            .unwrap_or_else(|e| exit_with(name, config_error, e));
+        (
+            config,
+            cli.verbose,
+            cli.dry_run,
+            false,
+            log::LevelFilter::Info,
+        )
+    };
+    // smtp_debug=true is the same as -v
+    let debug = config
+        .bool("smtp_debug", false)
+        .unwrap_or_else(|e| exit_with(name, config_error, e));
+    let level = match verbose || debug {
+        true => log::LevelFilter::Debug,
+        false => default_level,
+    };
+    // sendmail runs in whatever directory cron or PHP uses, no log file there
+    log4::init_log_to(level, (!sendmail_mode).then_some(log4::LOG_FILE));
 
     // Do as little as possible in main.rs as it can't contain any tests
-    let mut event_counter = 0;
-    let mut state_machine = state_machine::StateMachine::new_from_env();
+    if smtp_session {
+        return sendmail::session::serve(&config).await;
+    }
+    let mut state_machine = state_machine::StateMachine::new_from_config(&config)
+        .unwrap_or_else(|e| exit_with(name, config_error, e));
+    if dry_run {
+        let message = state_machine
+            .dry_run()
+            .unwrap_or_else(|e| exit_with(name, config_error, e));
+        io::stdout().write_all(&message)?;
+        return Ok(());
+    }
     log::info!(
         "Setup SMTP connection to {}:{}",
         state_machine.smtp_connection.host,
         state_machine.smtp_connection.port
     );
-    let mut current_state = state_machine.state.clone();
-    while match (&state_machine.state, event_counter) {
-        (state_machine::State::Start, _) => {
-            log::info!("Connecting to SMTP server...");
-            state_machine
-                .handle_event(state_events::Event::Connect)
-                .await;
-            true
-        }
-        (state_machine::State::Finished, i32::MIN..=15) => false,
-        (_, 16..=i32::MAX) => {
-            log::error!("Event counter exceeded 10 iterations, exiting.");
-            false
-        }
-        (state_machine::State::Failed, _) => {
-            log::error!("State machine failed, exiting.");
-            false
-        }
-        (_s, _i) => true,
-    } {
-        event_counter += 1;
-        log::debug!(
-            "Loop iteration: {} state:{:?}",
-            event_counter,
-            state_machine.state
-        );
-        // Check if the current stream is None
-        let new_event = state_events::get_event(&mut state_machine.smtp_connection).await;
-        log::info!("Current event: {:?}", new_event);
-        state_machine.handle_event(new_event).await;
-        if current_state != state_machine.state {
-            log::info!(
-                "State changed from {:?} to {:?}",
-                current_state,
-                state_machine.state
-            );
-            current_state = state_machine.state.clone();
-        }
-    }
-    // done
-    let final_event = state_events::get_event(&mut state_machine.smtp_connection).await;
-    log::info!("final event: {:?}", final_event);
-    // from lib.rs call connect_to_server
-    log::info!("SMTP Done server");
+    state_machine.run().await;
 
     //smtp_starttls::smtp_starttls(smtp_server, port).await
+    if state_machine.state != state_machine::State::Finished {
+        // sendmail callers such as an MTA or a mail queue retry on EX_TEMPFAIL
+        let code = match state_machine.error_reply() {
+            _ if !sendmail_mode => 1,
+            Some(reply) if reply.code >= 500 => sendmail::EX_UNAVAILABLE,
+            _ => sendmail::EX_TEMPFAIL,
+        };
+        process::exit(code);
+    }
     Ok(())
 }
+
+// report an error prefixed with the command name, without a panic backtrace
+fn exit_with(name: &str, code: i32, e: impl fmt::Display) -> ! {
+    eprintln!("{name}: {e}");
+    process::exit(code);
+}
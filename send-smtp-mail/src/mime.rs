@@ -0,0 +1,1017 @@
+use crate::address::Mailbox;
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine; // trait
+use rand::Rng;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub mod header;
+pub mod html;
+pub mod types;
+
+// RFC 2045: base64 and quoted-printable lines are at most 76 characters
+const BASE64_LINE_LENGTH: usize = 76;
+const QP_LINE_LENGTH: usize = 76;
+// RFC 5322: lines must not exceed 998 characters excluding CRLF
+pub const MAX_LINE_LENGTH: usize = 998;
+// Whole base64 lines per chunk when streaming files, 57 bytes encode to 76 characters
+pub const FILE_CHUNK_SIZE: usize = 57 * 1024;
+// Bytes read from the start of a file to guess its content type
+const SNIFF_LENGTH: usize = 512;
+// RFC 2231 parameter values longer than this are split into continuations
+const MAX_PARAMETER_LENGTH: usize = 60;
+
+/// Content-Transfer-Encoding of a single part
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferEncoding {
+    SevenBit,
+    EightBit, // RFC 6152, only when the server advertises 8BITMIME
+    QuotedPrintable,
+    Base64,
+}
+impl TransferEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransferEncoding::SevenBit => "7bit",
+            TransferEncoding::EightBit => "8bit",
+            TransferEncoding::QuotedPrintable => "quoted-printable",
+            TransferEncoding::Base64 => "base64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TransferEncoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "7bit" => Some(TransferEncoding::SevenBit),
+            "8bit" => Some(TransferEncoding::EightBit),
+            "quoted-printable" | "qp" => Some(TransferEncoding::QuotedPrintable),
+            "base64" => Some(TransferEncoding::Base64),
+            _ => None,
+        }
+    }
+
+    /// Pick the encoding for text: 7bit when it is ASCII with short lines,
+    /// quoted-printable when mostly ASCII so it stays readable, base64 otherwise
+    pub fn for_text(data: &[u8]) -> TransferEncoding {
+        let short_lines = data
+            .split(|&b| b == b'\n')
+            .all(|l| l.len() <= MAX_LINE_LENGTH);
+        if data.is_ascii() && short_lines && !data.contains(&0) {
+            return TransferEncoding::SevenBit;
+        }
+        // quoted-printable triples every non-ASCII byte, base64 grows everything by 4/3
+        let non_ascii = data.iter().filter(|b| !b.is_ascii()).count();
+        if non_ascii * 6 <= data.len() {
+            TransferEncoding::QuotedPrintable
+        } else {
+            TransferEncoding::Base64
+        }
+    }
+}
+
+/// Data of a leaf part. Files are only read while sending, chunk by chunk,
+/// so large attachments are never held in memory.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    File { path: PathBuf, len: u64 }, // always base64 encoded
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Content {
+    Single {
+        body: Body,
+        encoding: TransferEncoding,
+    },
+    // the boundary is chosen when rendering
+    Multipart(Vec<Part>),
+    // a complete message read from a file, sent as is
+    Raw(Vec<u8>),
+}
+
+/// One MIME entity, a leaf with data or a multipart holding further parts
+#[derive(Debug, PartialEq, Clone)]
+pub struct Part {
+    pub content_type: String, // e.g. "text/plain; charset=utf-8" or "multipart/mixed"
+    pub headers: Vec<(String, String)>, // extra headers e.g. Content-Disposition
+    pub content: Content,
+}
+
+impl Part {
+    pub fn text(text: &str) -> Part {
+        Part::text_with_type("text/plain; charset=utf-8", text)
+    }
+
+    pub fn html(html: &str) -> Part {
+        Part::text_with_type("text/html; charset=utf-8", html)
+    }
+
+    fn text_with_type(content_type: &str, text: &str) -> Part {
+        let data = normalize_line_endings(text.as_bytes());
+        Part {
+            content_type: content_type.to_string(),
+            headers: Vec::new(),
+            content: Content::Single {
+                encoding: TransferEncoding::for_text(&data),
+                body: Body::Bytes(data),
+            },
+        }
+    }
+
+    /// Override the transfer encoding chosen for a single part, 7bit is only
+    /// honoured for ASCII with short lines, 8bit for UTF-8 with short lines,
+    /// files are always base64
+    pub fn encoding(mut self, encoding: TransferEncoding) -> Part {
+        if let Content::Single {
+            body: Body::Bytes(data),
+            encoding: e,
+        } = &mut self.content
+        {
+            *e = match encoding {
+                TransferEncoding::SevenBit => match TransferEncoding::for_text(data) {
+                    TransferEncoding::SevenBit => encoding,
+                    _ => TransferEncoding::QuotedPrintable,
+                },
+                // ASCII stays 7bit, anything with NUL or long lines keeps its encoding
+                TransferEncoding::EightBit => match TransferEncoding::for_text(data) {
+                    TransferEncoding::SevenBit => TransferEncoding::SevenBit,
+                    _ if is_8bit_text(data) => encoding,
+                    chosen => chosen,
+                },
+                _ => encoding,
+            };
+        }
+        self
+    }
+
+    /// Whether this part or any below it is sent 8bit, needs BODY=8BITMIME
+    pub fn is_8bit(&self) -> bool {
+        match &self.content {
+            Content::Single { encoding, .. } => *encoding == TransferEncoding::EightBit,
+            Content::Multipart(parts) => parts.iter().any(Part::is_8bit),
+            Content::Raw(data) => !data.is_ascii(),
+        }
+    }
+
+    /// Any content, sent base64 encoded
+    pub fn binary(content_type: &str, data: Vec<u8>) -> Part {
+        Part::with_body(content_type, Body::Bytes(data))
+    }
+
+    /// Base64 encoded content streamed from a file when the message is sent
+    pub fn file(content_type: &str, path: &Path, len: u64) -> Part {
+        let path = path.to_path_buf();
+        Part::with_body(content_type, Body::File { path, len })
+    }
+
+    fn with_body(content_type: &str, body: Body) -> Part {
+        Part {
+            content_type: content_type.to_string(),
+            headers: Vec::new(),
+            content: Content::Single {
+                body,
+                encoding: TransferEncoding::Base64,
+            },
+        }
+    }
+
+    /// `subtype` is mixed, alternative, related ...
+    pub fn multipart(subtype: &str, parts: Vec<Part>) -> Part {
+        Part {
+            content_type: format!("multipart/{subtype}"),
+            headers: Vec::new(),
+            content: Content::Multipart(parts),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Part {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Headers and body of this part, ending with CRLF. File content is left
+    /// as a `Segment::File` for the sender to stream.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut head = Vec::new();
+        match &self.content {
+            Content::Raw(data) => vec![Segment::Bytes(data.clone())],
+            Content::Single { body, encoding } => {
+                push_header(&mut head, "Content-Type", &self.content_type);
+                push_header(&mut head, "Content-Transfer-Encoding", encoding.name());
+                for (name, value) in &self.headers {
+                    push_header(&mut head, name, value);
+                }
+                head.extend_from_slice(b"\r\n");
+                match body {
+                    Body::Bytes(data) => {
+                        head.extend_from_slice(&encode(data, *encoding));
+                        vec![Segment::Bytes(head)]
+                    }
+                    Body::File { path, len } => vec![
+                        Segment::Bytes(head),
+                        Segment::File {
+                            path: path.clone(),
+                            len: *len,
+                        },
+                    ],
+                }
+            }
+            Content::Multipart(parts) => {
+                let rendered: Vec<Vec<Segment>> = parts.iter().map(Part::segments).collect();
+                // files are base64 which can not contain the boundary, only check the rest
+                let boundary = unique_boundary(rendered.iter().flatten().filter_map(|s| match s {
+                    Segment::Bytes(bytes) => Some(bytes.as_slice()),
+                    Segment::File { .. } => None,
+                }));
+                push_header(
+                    &mut head,
+                    "Content-Type",
+                    &format!("{}; boundary=\"{}\"", self.content_type, boundary),
+                );
+                for (name, value) in &self.headers {
+                    push_header(&mut head, name, value);
+                }
+                head.extend_from_slice(b"\r\n");
+                let mut segments = vec![Segment::Bytes(head)];
+                for part in rendered {
+                    push_segment(
+                        &mut segments,
+                        Segment::Bytes(format!("--{boundary}\r\n").into_bytes()),
+                    );
+                    for segment in part {
+                        push_segment(&mut segments, segment);
+                    }
+                }
+                push_segment(
+                    &mut segments,
+                    Segment::Bytes(format!("--{boundary}--\r\n").into_bytes()),
+                );
+                segments
+            }
+        }
+    }
+}
+
+/// A piece of a rendered message, either ready to send or a file to stream
+#[derive(Debug, PartialEq, Clone)]
+pub enum Segment {
+    Bytes(Vec<u8>),
+    File { path: PathBuf, len: u64 }, // to be base64 encoded while sending
+}
+
+impl Segment {
+    /// Size after transfer encoding, before dot-stuffing
+    pub fn encoded_len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::File { len, .. } => base64_len(*len),
+        }
+    }
+}
+
+// Adjacent byte segments are merged so the sender writes fewer, larger pieces
+fn push_segment(segments: &mut Vec<Segment>, segment: Segment) {
+    match (segments.last_mut(), segment) {
+        (Some(Segment::Bytes(last)), Segment::Bytes(bytes)) => last.extend_from_slice(&bytes),
+        (_, segment) => segments.push(segment),
+    }
+}
+
+// Length of base64 output wrapped at 76 characters with CRLF line ends
+fn base64_len(len: u64) -> u64 {
+    let chars = len.div_ceil(3) * 4;
+    chars + chars.div_ceil(BASE64_LINE_LENGTH as u64) * 2
+}
+
+/// Incremental base64 encoder producing the same 76 character CRLF lines as
+/// `encode`, input may arrive in chunks of any size
+#[derive(Default)]
+pub struct Base64Encoder {
+    carry: Vec<u8>, // input not yet making up a full line
+}
+
+impl Base64Encoder {
+    pub fn new() -> Self {
+        Base64Encoder::default()
+    }
+
+    pub fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        const LINE_INPUT: usize = BASE64_LINE_LENGTH / 4 * 3; // 57 bytes
+        self.carry.extend_from_slice(chunk);
+        let full = self.carry.len() / LINE_INPUT * LINE_INPUT;
+        let out = encode(&self.carry[..full], TransferEncoding::Base64);
+        self.carry.drain(..full);
+        out
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        let out = encode(&self.carry, TransferEncoding::Base64);
+        self.carry.clear();
+        out
+    }
+}
+
+/// A file sent with the message, or shown inline when it has a Content-ID
+#[derive(Debug, PartialEq, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub body: Body,
+    pub content_id: Option<String>, // referenced from HTML as cid:<content_id>
+}
+
+impl Attachment {
+    /// Content type is guessed from the filename and data, see `types::guess`
+    pub fn new(filename: &str, data: Vec<u8>) -> Attachment {
+        Attachment {
+            filename: filename.to_string(),
+            content_type: types::guess(filename, &data).to_string(),
+            body: Body::Bytes(data),
+            content_id: None,
+        }
+    }
+
+    /// A file streamed from disk when sending, only its start is read now to
+    /// guess the content type. Named after the last path component.
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Attachment> {
+        let path = path.as_ref();
+        let context = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read attachment {}: {}", path.display(), e),
+            )
+        };
+        let file = std::fs::File::open(path).map_err(context)?;
+        let len = file.metadata().map_err(context)?.len();
+        let mut head = Vec::with_capacity(SNIFF_LENGTH);
+        file.take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut head)
+            .map_err(context)?;
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string());
+        Ok(Attachment {
+            content_type: types::guess(&filename, &head).to_string(),
+            filename,
+            body: Body::File {
+                path: path.to_path_buf(),
+                len,
+            },
+            content_id: None,
+        })
+    }
+
+    /// Override the guessed content type
+    pub fn content_type(mut self, content_type: &str) -> Attachment {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    pub fn inline(mut self, content_id: &str) -> Attachment {
+        self.content_id = Some(content_id.to_string());
+        self
+    }
+
+    pub fn to_part(&self) -> Part {
+        // name= is not standard but older clients only look at the Content-Type
+        let content_type = format!(
+            "{}; {}",
+            self.content_type,
+            parameter("name", &self.filename)
+        );
+        let part = Part::with_body(&content_type, self.body.clone());
+        match &self.content_id {
+            Some(cid) => part
+                .header(
+                    "Content-Disposition",
+                    &format!("inline; {}", parameter("filename", &self.filename)),
+                )
+                .header("Content-ID", &format!("<{cid}>")),
+            None => part.header(
+                "Content-Disposition",
+                &format!("attachment; {}", parameter("filename", &self.filename)),
+            ),
+        }
+    }
+}
+
+/// A complete RFC 5322 message: top level headers and a MIME body
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub headers: Vec<(String, String)>,
+    pub body: Part,
+}
+
+impl Message {
+    pub fn builder() -> MessageBuilder {
+        MessageBuilder::default()
+    }
+
+    /// Value of the first header with this name, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// A complete message, e.g. an .eml file or sendmail input, sent as is apart
+    /// from CRLF line endings and dot-stuffing. Headers are parsed for lookups only.
+    pub fn from_raw(data: Vec<u8>) -> io::Result<Message> {
+        let headers = parse_headers(&data)?;
+        Ok(Message {
+            headers,
+            body: Part {
+                content_type: String::new(),
+                headers: Vec::new(),
+                content: Content::Raw(data),
+            },
+        })
+    }
+
+    /// Values of all headers with this name, case insensitive
+    pub fn headers(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Remove all headers with this name, e.g. Bcc after taking the recipients
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        if let Content::Raw(data) = &mut self.body.content {
+            *data = remove_raw_header(data, name);
+        }
+    }
+
+    /// Add From, Date and Message-ID when missing, as sendmail does for messages
+    /// from scripts. Only for raw messages, built ones always have them.
+    pub fn complete_headers(&mut self, from: &Mailbox) {
+        let Content::Raw(data) = &mut self.body.content else {
+            return;
+        };
+        let domain = from.address().rsplit_once('@').map_or("", |(_, d)| d);
+        let missing: Vec<(&str, String)> = [
+            ("From", from.to_header()),
+            ("Date", chrono::Local::now().to_rfc2822()),
+            ("Message-ID", header::message_id(domain)),
+        ]
+        .into_iter()
+        .filter(|(name, _)| {
+            !self
+                .headers
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case(name))
+        })
+        .collect();
+        let mut head = Vec::new();
+        for (name, value) in missing {
+            push_header(&mut head, name, &value);
+            self.headers.push((name.to_string(), value));
+        }
+        data.splice(0..0, head);
+    }
+
+    /// The Message-ID including angle brackets, for tracking the mail after sending
+    pub fn message_id(&self) -> Option<&str> {
+        self.header("Message-ID")
+    }
+
+    /// The message as sent after DATA, without dot-stuffing and the terminating "."
+    pub fn segments(&self) -> Vec<Segment> {
+        if let Content::Raw(_) = &self.body.content {
+            return self.body.segments();
+        }
+        let mut head = Vec::new();
+        for (name, value) in &self.headers {
+            push_header(&mut head, name, value);
+        }
+        push_header(&mut head, "MIME-Version", "1.0");
+        let mut segments = vec![Segment::Bytes(head)];
+        for segment in self.body.segments() {
+            push_segment(&mut segments, segment);
+        }
+        segments
+    }
+
+    /// Needs BODY=8BITMIME: 8bit parts or UTF-8 headers
+    pub fn is_8bit(&self) -> bool {
+        self.body.is_8bit() || self.has_utf8_headers()
+    }
+
+    /// Needs SMTPUTF8 (RFC 6532): header values with raw UTF-8
+    pub fn has_utf8_headers(&self) -> bool {
+        self.headers.iter().any(|(_, value)| !value.is_ascii())
+    }
+
+    /// Size of the message after transfer encoding, files included
+    pub fn encoded_len(&self) -> u64 {
+        self.segments().iter().map(Segment::encoded_len).sum()
+    }
+
+    /// The whole message in memory, files are read and encoded
+    pub fn render(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for segment in self.segments() {
+            match segment {
+                Segment::Bytes(bytes) => out.extend_from_slice(&bytes),
+                Segment::File { path, .. } => {
+                    out.extend_from_slice(&encode(&std::fs::read(path)?, TransferEncoding::Base64))
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+// Header values are encoded in build, once it is known whether UTF-8 may be sent as is
+#[derive(Debug, Clone)]
+enum HeaderValue {
+    Raw(String),             // sent as given
+    Text(String),            // unstructured, e.g. Subject
+    Mailboxes(Vec<Mailbox>), // address list, e.g. From or To
+}
+
+#[derive(Debug, Default)]
+pub struct MessageBuilder {
+    headers: Vec<(String, HeaderValue)>,
+    utf8: bool,      // RFC 6532 UTF-8 headers, the server must support SMTPUTF8
+    eight_bit: bool, // 8bit text parts, the server must support 8BITMIME
+    from_domain: Option<String>, // for the generated Message-ID
+    text_encoding: Option<TransferEncoding>, // chosen from the content when None
+    text: Option<String>,
+    html: Option<String>,
+    attachments: Vec<Attachment>,
+    inline: Vec<Attachment>,
+    parts: Vec<Part>,
+}
+
+impl MessageBuilder {
+    /// Any header e.g. In-Reply-To, References or List-Unsubscribe. The value is sent
+    /// as given, `build` fails if it contains line breaks. Date and Message-ID
+    /// given here replace the generated ones.
+    pub fn header(self, name: &str, value: &str) -> Self {
+        self.push(name, HeaderValue::Raw(value.to_string()))
+    }
+    fn push(mut self, name: &str, value: HeaderValue) -> Self {
+        self.headers.push((name.to_string(), value));
+        self
+    }
+    pub fn from(mut self, from: &Mailbox) -> Self {
+        self.from_domain = from.address().rsplit_once('@').map(|(_, d)| d.to_string());
+        self.push("From", HeaderValue::Mailboxes(vec![from.clone()]))
+    }
+    pub fn to(self, to: &[Mailbox]) -> Self {
+        self.push("To", HeaderValue::Mailboxes(to.to_vec()))
+    }
+    pub fn cc(self, cc: &[Mailbox]) -> Self {
+        if cc.is_empty() {
+            return self;
+        }
+        self.push("Cc", HeaderValue::Mailboxes(cc.to_vec()))
+    }
+    pub fn reply_to(self, reply_to: &[Mailbox]) -> Self {
+        if reply_to.is_empty() {
+            return self;
+        }
+        self.push("Reply-To", HeaderValue::Mailboxes(reply_to.to_vec()))
+    }
+    /// Non-ASCII subjects are sent as RFC 2047 encoded words unless `utf8` is set
+    pub fn subject(self, subject: &str) -> Self {
+        self.push("Subject", HeaderValue::Text(subject.to_string()))
+    }
+    /// Send subject and display names as raw UTF-8 (RFC 6532) instead of encoded
+    /// words and allow non-ASCII addresses, only when the server has SMTPUTF8
+    pub fn utf8(mut self, utf8: bool) -> Self {
+        self.utf8 = utf8;
+        self
+    }
+    /// Send non-ASCII text parts 8bit instead of quoted-printable or base64, only
+    /// when the server has 8BITMIME
+    pub fn eight_bit(mut self, eight_bit: bool) -> Self {
+        self.eight_bit = eight_bit;
+        self
+    }
+    /// X-Priority 1 (highest) to 5 (lowest), 3 is normal
+    pub fn priority(self, priority: u8) -> Self {
+        self.header("X-Priority", header::priority(priority))
+    }
+    pub fn text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+    /// Without `text` a plaintext alternative is generated from the HTML
+    pub fn html(mut self, html: &str) -> Self {
+        self.html = Some(html.to_string());
+        self
+    }
+    /// Force the transfer encoding of the text and HTML parts
+    pub fn text_encoding(mut self, encoding: TransferEncoding) -> Self {
+        self.text_encoding = Some(encoding);
+        self
+    }
+    pub fn attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+    /// Inline part, e.g. an image referenced from the HTML body as cid:<content_id>
+    pub fn inline(mut self, attachment: Attachment) -> Self {
+        self.inline.push(attachment);
+        self
+    }
+    /// Any extra part, e.g. a nested multipart built by hand
+    pub fn part(mut self, part: Part) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    /// Fails when a header could inject further headers, see `header::check`, or
+    /// contains non-ASCII that can not be encoded without `utf8`
+    pub fn build(self) -> io::Result<Message> {
+        let utf8 = self.utf8;
+        let headers: Vec<(String, String)> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), header_value(value, utf8)))
+            .collect();
+        for (name, value) in &headers {
+            header::check(name, value)?;
+            if !utf8 && !value.is_ascii() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Header {name} has non-ASCII characters that need SMTPUTF8: {value:?}"),
+                ));
+            }
+        }
+        for attachment in self.attachments.iter().chain(&self.inline) {
+            header::check("Content-Type", &attachment.content_type)?;
+            if let Some(cid) = &attachment.content_id {
+                header::check("Content-ID", cid)?;
+            }
+        }
+        let mut headers = headers;
+        // RFC 5322 requires Date, Message-ID is expected by most relays and spam filters
+        if !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("Date")) {
+            headers.insert(0, ("Date".to_string(), chrono::Local::now().to_rfc2822()));
+        }
+        if !headers
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case("Message-ID"))
+        {
+            let domain = self.from_domain.unwrap_or_default();
+            headers.push(("Message-ID".to_string(), header::message_id(&domain)));
+        }
+        // multipart/alternative [ text, multipart/related [ html, inline images ] ]
+        // so clients showing the text version do not list the images as attachments
+        let inline: Vec<Part> = self.inline.iter().map(Attachment::to_part).collect();
+        let related = |first: Part| match inline.is_empty() {
+            true => first,
+            false => Part::multipart("related", [vec![first], inline.clone()].concat()),
+        };
+        let encode = |part: Part| match self.text_encoding {
+            Some(encoding) => part.encoding(encoding),
+            None if self.eight_bit => part.encoding(TransferEncoding::EightBit),
+            None => part,
+        };
+        let mut body = match (&self.text, &self.html) {
+            (text, Some(html)) => {
+                let text = text.clone().unwrap_or_else(|| html::to_text(html));
+                Part::multipart(
+                    "alternative",
+                    vec![encode(Part::text(&text)), related(encode(Part::html(html)))],
+                )
+            }
+            (Some(text), None) => related(encode(Part::text(text))),
+            (None, None) => related(Part::text("")),
+        };
+        if !self.attachments.is_empty() || !self.parts.is_empty() {
+            let mut parts = vec![body];
+            parts.extend(self.attachments.iter().map(Attachment::to_part));
+            parts.extend(self.parts);
+            body = Part::multipart("mixed", parts);
+        }
+        Ok(Message { headers, body })
+    }
+}
+
+/// A header parameter `name="value"`, values that are not plain ASCII or too long
+/// for one line use RFC 2231 percent encoding and continuations:
+/// `filename*0*=utf-8''%C3%A9t%C3%A9;\r\n filename*1*=.pdf`
+pub fn parameter(name: &str, value: &str) -> String {
+    let plain = value.len() <= MAX_PARAMETER_LENGTH
+        && value
+            .bytes()
+            .all(|b| (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\');
+    if plain {
+        return format!("{name}=\"{value}\"");
+    }
+    let encoded: Vec<String> = value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    // split between encoded bytes so no %XX triplet is cut in half
+    let mut sections = vec![String::from("utf-8''")];
+    for e in encoded {
+        if sections.last().unwrap().len() + e.len() > MAX_PARAMETER_LENGTH {
+            sections.push(String::new());
+        }
+        sections.last_mut().unwrap().push_str(&e);
+    }
+    if sections.len() == 1 {
+        return format!("{name}*={}", sections[0]);
+    }
+    sections
+        .iter()
+        .enumerate()
+        .map(|(i, section)| format!("{name}*{i}*={section}"))
+        .collect::<Vec<String>>()
+        .join(";\r\n ")
+}
+
+// Unfolded header fields up to the first empty line, LF or CRLF line endings
+fn parse_headers(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in data.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push_str(&line);
+                continue;
+            }
+        }
+        let field = line
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic()));
+        let Some((name, value)) = field else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not a message header line: {:?}", line),
+            ));
+        };
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    if headers.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message has no headers",
+        ));
+    }
+    Ok(headers)
+}
+
+// Drop a header and its continuation lines from the header block of a raw message
+fn remove_raw_header(data: &[u8], name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut removing = false;
+    let mut lines = data.split_inclusive(|&b| b == b'\n');
+    for line in lines.by_ref() {
+        if line == b"\n" || line == b"\r\n" {
+            out.extend_from_slice(line);
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            removing = line.len() > name.len()
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+                && line[name.len()] == b':';
+        }
+        if !removing {
+            out.extend_from_slice(line);
+        }
+    }
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+    out
+}
+
+fn header_value(value: &HeaderValue, utf8: bool) -> String {
+    match value {
+        HeaderValue::Raw(value) => value.clone(),
+        // "=?" would be taken as the start of an encoded word
+        HeaderValue::Text(text) if utf8 && !text.contains("=?") => text.clone(),
+        HeaderValue::Text(text) => header::encode_text(text),
+        HeaderValue::Mailboxes(mailboxes) => mailboxes
+            .iter()
+            .map(|m| match utf8 {
+                true => m.to_utf8_header(),
+                false => m.to_header(),
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+    }
+}
+
+// 8bit content: lines of at most 998 octets and no NUL (RFC 2045 section 2.8)
+fn is_8bit_text(data: &[u8]) -> bool {
+    !data.contains(&0)
+        && data
+            .split(|&b| b == b'\n')
+            .all(|l| l.len() <= MAX_LINE_LENGTH)
+}
+
+fn push_header(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.extend_from_slice(header::fold(&format!("{name}: {value}")).as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Encode part data, the result always ends with CRLF
+pub fn encode(data: &[u8], encoding: TransferEncoding) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 4 / 3 + data.len() / 38 + 4);
+    match encoding {
+        TransferEncoding::SevenBit | TransferEncoding::EightBit => {
+            out.extend_from_slice(&normalize_line_endings(data));
+            if !out.is_empty() && !out.ends_with(b"\r\n") {
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+        TransferEncoding::QuotedPrintable => {
+            quoted_printable(&normalize_line_endings(data), &mut out);
+        }
+        TransferEncoding::Base64 => {
+            for line in b64.encode(data).as_bytes().chunks(BASE64_LINE_LENGTH) {
+                out.extend_from_slice(line);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+    out
+}
+
+// RFC 2045 section 6.7, CRLF in the text are hard line breaks, longer lines get
+// soft breaks "=" CRLF so no encoded line exceeds 76 characters
+fn quoted_printable(text: &[u8], out: &mut Vec<u8>) {
+    let text = text.strip_suffix(b"\r\n").unwrap_or(text);
+    for line in text.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut length = 0;
+        for (i, &b) in line.iter().enumerate() {
+            let last = i + 1 == line.len();
+            let literal = match b {
+                b'=' => false,
+                b' ' | b'\t' => !last, // trailing whitespace would be stripped in transit
+                b'!'..=b'~' => true,
+                _ => false,
+            };
+            let width = if literal { 1 } else { 3 };
+            // keep room for the "=" of a soft break unless this is the end of the line
+            let limit = if last {
+                QP_LINE_LENGTH
+            } else {
+                QP_LINE_LENGTH - 1
+            };
+            if length + width > limit {
+                out.extend_from_slice(b"=\r\n");
+                length = 0;
+            }
+            if literal {
+                out.push(b);
+            } else {
+                out.extend_from_slice(format!("={:02X}", b).as_bytes());
+            }
+            length += width;
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Convert bare LF and bare CR to CRLF
+pub fn normalize_line_endings(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 40);
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'\r' if data.get(i + 1) == Some(&b'\n') => {
+                out.extend_from_slice(b"\r\n");
+                i += 1;
+            }
+            b'\r' | b'\n' => out.extend_from_slice(b"\r\n"),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+// Random boundary that does not occur in any of the rendered parts. "=_" can not
+// appear in base64 or quoted-printable output so a collision needs literal text.
+fn unique_boundary<'a>(parts: impl Iterator<Item = &'a [u8]> + Clone) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let boundary = format!("=_{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>());
+        let collides = parts.clone().any(|part| {
+            part.windows(boundary.len())
+                .any(|w| w == boundary.as_bytes())
+        });
+        if !collides {
+            return boundary;
+        }
+        log::warn!(
+            "Boundary {} found in message content, picking another",
+            boundary
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qp(text: &str) -> String {
+        String::from_utf8(encode(text.as_bytes(), TransferEncoding::QuotedPrintable)).unwrap()
+    }
+
+    #[test]
+    fn qp_trailing_whitespace() {
+        assert_eq!(qp("a b \nc\t\nd"), "a b=20\r\nc=09\r\nd\r\n");
+    }
+
+    #[test]
+    fn qp_escapes() {
+        assert_eq!(qp("1+1=2 caf\u{e9}\r\n"), "1+1=3D2 caf=C3=A9\r\n");
+    }
+
+    #[test]
+    fn qp_soft_breaks_at_76_columns() {
+        assert_eq!(qp(&"a".repeat(76)), format!("{}\r\n", "a".repeat(76)));
+        let long = qp(&"b".repeat(200));
+        let lines: Vec<&str> = long.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("{}=", "b".repeat(75)));
+        assert_eq!(lines[1], format!("{}=", "b".repeat(75)));
+        assert_eq!(lines[2], "b".repeat(50));
+    }
+
+    #[test]
+    fn qp_soft_break_does_not_split_escapes() {
+        let encoded = qp(&format!("{}\u{e9}\u{e9}", "x".repeat(72)));
+        for line in encoded.split_terminator("\r\n") {
+            assert!(line.len() <= 76, "{line:?}");
+        }
+        assert_eq!(encoded, format!("{}=C3=\r\n=A9=C3=A9\r\n", "x".repeat(72)));
+        // the decoded text is unchanged once the soft breaks are removed
+        assert_eq!(
+            encoded.replace("=\r\n", ""),
+            format!("{}=C3=A9=C3=A9\r\n", "x".repeat(72))
+        );
+    }
+
+    #[test]
+    fn seven_bit_and_base64() {
+        assert_eq!(encode(b"a\nb", TransferEncoding::SevenBit), b"a\r\nb\r\n");
+        assert_eq!(encode(b"", TransferEncoding::SevenBit), b"");
+        let encoded = encode(&[0u8; 120], TransferEncoding::Base64);
+        let lines: Vec<&[u8]> = encoded.split(|&b| b == b'\n').collect();
+        assert_eq!(lines[0].len(), BASE64_LINE_LENGTH + 1); // with the CR
+        assert!(encoded.ends_with(b"\r\n"));
+    }
+
+    #[test]
+    fn base64_encoder_in_pieces() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 256) as u8).collect();
+        for size in [1, 3, 56, 57, 58, 200] {
+            let mut encoder = Base64Encoder::new();
+            let mut out = Vec::new();
+            for piece in data.chunks(size) {
+                out.extend(encoder.encode(piece));
+            }
+            out.extend(encoder.finish());
+            assert_eq!(out, encode(&data, TransferEncoding::Base64), "{size}");
+        }
+        assert_eq!(
+            Base64Encoder::new().finish(),
+            encode(b"", TransferEncoding::Base64)
+        );
+    }
+
+    #[test]
+    fn remove_raw_bcc() {
+        let raw = b"To: a@example.com\r\nBcc: b@example.com,\r\n c@example.com\r\n\
+                    Subject: hi\r\n\r\nBcc: kept in the body\r\n";
+        let mut message = Message::from_raw(raw.to_vec()).unwrap();
+        assert_eq!(message.headers("bcc").len(), 1);
+        message.remove_header("Bcc");
+        assert_eq!(message.header("Bcc"), None);
+        assert_eq!(
+            message.render().unwrap(),
+            b"To: a@example.com\r\nSubject: hi\r\n\r\nBcc: kept in the body\r\n"
+        );
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(normalize_line_endings(b"a\rb\nc\r\n"), b"a\r\nb\r\nc\r\n");
+    }
+}
@@ -0,0 +1,282 @@
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine; // trait
+use std::io;
+
+// RFC 5322: lines should not be longer than 78 characters excluding CRLF
+pub const FOLD_LINE_LENGTH: usize = 78;
+// RFC 2047: an encoded word is at most 75 characters including =?utf-8?q? and ?=
+const MAX_ENCODED_WORD_LENGTH: usize = 75;
+const ENCODED_WORD_OVERHEAD: usize = "=?utf-8?q??=".len();
+
+/// Unstructured header value like Subject, non-ASCII text becomes encoded words
+pub fn encode_text(value: &str) -> String {
+    if is_plain(value) && !value.contains("=?") {
+        return value.to_string();
+    }
+    encode_words(value)
+}
+
+/// Display name in an address header, quoted when it contains specials
+pub fn encode_phrase(name: &str) -> String {
+    if !is_plain(name) || name.contains("=?") {
+        return encode_words(name);
+    }
+    let atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~ ".contains(c);
+    if name.chars().all(atext) {
+        return name.to_string();
+    }
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Display name for a message sent with SMTPUTF8, RFC 6532 allows UTF-8 in
+/// atext so only specials need quoting
+pub fn utf8_phrase(name: &str) -> String {
+    let atext =
+        |c: char| !c.is_ascii() || c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~ ".contains(c);
+    if name.chars().all(atext) && !name.contains("=?") {
+        return name.to_string();
+    }
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Printable ASCII and spaces only
+fn is_plain(value: &str) -> bool {
+    value.chars().all(|c| c == ' ' || c.is_ascii_graphic())
+}
+
+/// Encode as a sequence of RFC 2047 encoded words separated by spaces, each at most
+/// 75 characters. Q is used for mostly ASCII text so it stays readable, B otherwise.
+/// Words are split between characters, a multi byte character is never cut in half.
+pub fn encode_words(value: &str) -> String {
+    let non_ascii = value.bytes().filter(|b| !b.is_ascii()).count();
+    let q = non_ascii * 3 <= value.len();
+    let max_payload = MAX_ENCODED_WORD_LENGTH - ENCODED_WORD_OVERHEAD;
+    let mut words: Vec<String> = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        let mut candidate = chunk.clone();
+        candidate.push(c);
+        let length = if q {
+            q_encode(&candidate).len()
+        } else {
+            candidate.len().div_ceil(3) * 4
+        };
+        if length > max_payload && !chunk.is_empty() {
+            words.push(chunk);
+            chunk = c.to_string();
+        } else {
+            chunk = candidate;
+        }
+    }
+    if !chunk.is_empty() || words.is_empty() {
+        words.push(chunk);
+    }
+    words
+        .iter()
+        .map(|w| match q {
+            true => format!("=?utf-8?q?{}?=", q_encode(w)),
+            false => format!("=?utf-8?b?{}?=", b64.encode(w)),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// RFC 2047 Q encoding restricted to the characters allowed in a phrase
+fn q_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'*' | b'+' | b'-' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("={:02X}", b),
+        })
+        .collect()
+}
+
+/// Fold a "Name: value" header line at whitespace so lines stay within 78
+/// characters where possible. Existing folds (CRLF + space) are kept.
+/// A single word longer than the limit is left on its own line.
+pub fn fold(line: &str) -> String {
+    line.split("\r\n")
+        .map(fold_line)
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+fn fold_line(line: &str) -> String {
+    if line.len() <= FOLD_LINE_LENGTH {
+        return line.to_string();
+    }
+    let mut out = String::new();
+    let mut current = String::new();
+    // split before each space, the space starts the continuation line
+    let mut rest = line;
+    while !rest.is_empty() {
+        let next = rest
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == ' ')
+            .map_or(rest.len(), |(i, _)| i);
+        let (word, tail) = rest.split_at(next);
+        // never fold directly after "Name:", the first word stays on the header line
+        let breakable = match out.is_empty() {
+            true => current.contains(' '),
+            false => !current.trim().is_empty(),
+        };
+        if breakable && current.len() + word.len() > FOLD_LINE_LENGTH {
+            out.push_str(&current);
+            out.push_str("\r\n");
+            current.clear();
+        }
+        current.push_str(word);
+        rest = tail;
+    }
+    out.push_str(&current);
+    out
+}
+
+/// Reject header names and values that would let the caller inject extra headers
+/// or end the header block. Line breaks are only allowed as folding whitespace,
+/// CRLF followed by a space or tab.
+pub fn check(name: &str, value: &str) -> io::Result<()> {
+    let invalid = |reason: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid header {name:?}: {reason}"),
+        ))
+    };
+    // RFC 5322 field name: printable ASCII except ':'
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+        return invalid("name must be printable ASCII without ':'");
+    }
+    let bytes = value.as_bytes();
+    for (i, b) in bytes.iter().enumerate() {
+        let folded = match b {
+            b'\r' => {
+                bytes.get(i + 1) == Some(&b'\n') && matches!(bytes.get(i + 2), Some(b' ' | b'\t'))
+            }
+            b'\n' => {
+                i > 0 && bytes[i - 1] == b'\r' && matches!(bytes.get(i + 1), Some(b' ' | b'\t'))
+            }
+            b'\0' => false,
+            _ => true,
+        };
+        if !folded {
+            return invalid("value contains a line break or NUL");
+        }
+    }
+    Ok(())
+}
+
+/// Globally unique Message-ID `<time.random@domain>`, the domain is taken from
+/// the sender address so it is meaningful to the recipient
+pub fn message_id(domain: &str) -> String {
+    let domain = match domain.is_ascii() && !domain.is_empty() && !domain.starts_with('[') {
+        true => domain,
+        false => "localhost", // punycode is not supported, and literals are not allowed
+    };
+    let millis = chrono::Utc::now().timestamp_millis();
+    format!("<{:x}.{:016x}@{}>", millis, rand::random::<u64>(), domain)
+}
+
+/// X-Priority value for 1 (highest) to 5 (lowest), anything else is normal
+pub fn priority(priority: u8) -> &'static str {
+    match priority {
+        1 => "1 (Highest)",
+        2 => "2 (High)",
+        4 => "4 (Low)",
+        5 => "5 (Lowest)",
+        _ => "3 (Normal)",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the bytes of one encoded word, to check where the words were split
+    fn decode_word(word: &str) -> Vec<u8> {
+        if let Some(text) = word.strip_prefix("=?utf-8?b?") {
+            return b64.decode(text.strip_suffix("?=").unwrap()).unwrap();
+        }
+        let text = word.strip_prefix("=?utf-8?q?").unwrap();
+        let text = text.strip_suffix("?=").unwrap().as_bytes();
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            match text[i] {
+                b'_' => out.push(b' '),
+                b'=' => {
+                    let hex = std::str::from_utf8(&text[i + 1..i + 3]).unwrap();
+                    out.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 2;
+                }
+                b => out.push(b),
+            }
+            i += 1;
+        }
+        out
+    }
+
+    fn check_words(value: &str) {
+        let encoded = encode_words(value);
+        let mut decoded = String::new();
+        for word in encoded.split(' ') {
+            assert!(word.len() <= MAX_ENCODED_WORD_LENGTH, "{word}");
+            // each word is valid UTF-8 on its own, no character is cut in half
+            decoded.push_str(&String::from_utf8(decode_word(word)).unwrap());
+        }
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(encode_text("Plain subject"), "Plain subject");
+        assert_eq!(encode_text("a =?b?= c"), "=?utf-8?q?a_=3D=3Fb=3F=3D_c?=");
+        check_words(&"Überprüfung des Jahresberichts für das Geschäftsjahr ".repeat(3));
+        check_words(&"日本語のテキスト🎉".repeat(8));
+        check_words("é");
+    }
+
+    #[test]
+    fn phrases() {
+        assert_eq!(encode_phrase("Ops Bot"), "Ops Bot");
+        assert_eq!(encode_phrase("Ops, Bot \"Q\""), "\"Ops, Bot \\\"Q\\\"\"");
+        assert_eq!(utf8_phrase("Jörg Müller"), "Jörg Müller");
+        assert_eq!(utf8_phrase("Müller, Jörg"), "\"Müller, Jörg\"");
+    }
+
+    #[test]
+    fn fold_long_lines() {
+        let line = format!("Subject: {}", "word ".repeat(40).trim_end());
+        let folded = fold(&line);
+        assert!(folded.split("\r\n").all(|l| l.len() <= FOLD_LINE_LENGTH));
+        assert!(folded.split("\r\n").skip(1).all(|l| l.starts_with(' ')));
+        assert_eq!(folded.replace("\r\n", ""), line);
+        // the first word stays after the name, a word too long is not split
+        let long = format!("References: <{}@example.com> <a@b>", "x".repeat(90));
+        let folded = fold(&long);
+        assert!(folded.starts_with("References: <xxx"));
+        assert_eq!(folded.replace("\r\n", ""), long);
+        assert_eq!(fold("Subject: short"), "Subject: short");
+    }
+
+    #[test]
+    fn check_rejects_injection() {
+        assert!(check("Subject", "folded\r\n continued\r\n\tagain").is_ok());
+        for value in [
+            "a\r\nBcc: x@example.com",
+            "a\nb",
+            "a\rb",
+            "a\r\n",
+            "a\r\n\r\nbody",
+            "a\0b",
+        ] {
+            assert!(check("Subject", value).is_err(), "{value:?}");
+        }
+        for name in ["", "Bad:Name", "Bad Name", "X-\r\nBcc"] {
+            assert!(check(name, "value").is_err(), "{name:?}");
+        }
+    }
+}
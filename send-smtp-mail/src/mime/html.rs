@@ -0,0 +1,240 @@
+// Plaintext alternative for HTML only mails. Not a full HTML parser, good enough
+// for generated reports: block elements become line breaks, links keep their
+// target, lists get bullets, script and style content is dropped.
+
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "table",
+    "tr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "blockquote",
+    "pre",
+    "hr",
+    "section",
+    "article",
+    "header",
+    "footer",
+];
+
+/// Convert HTML to readable plain text
+pub fn to_text(html: &str) -> String {
+    let mut out = String::new();
+    let mut links: Vec<String> = Vec::new(); // href of each open <a>
+    let mut pre: u32 = 0; // inside <pre>, keep whitespace
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        push_text(&mut out, &rest[..start], pre > 0);
+        rest = &rest[start..];
+        // comments may contain '>'
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            push_text(&mut out, rest, pre > 0);
+            rest = "";
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match (name.as_str(), closing) {
+            ("script" | "style" | "head" | "title", false) => {
+                // skip everything up to the matching closing tag
+                let close = format!("</{name}");
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => rest[i..].find('>').map_or("", |e| &rest[i + e + 1..]),
+                    None => "",
+                };
+            }
+            ("br", _) => out.push('\n'),
+            ("li", false) => {
+                new_line(&mut out);
+                out.push_str("* ");
+            }
+            ("td" | "th", true) => out.push('\t'),
+            ("a", false) => links.push(attribute(tag, "href").unwrap_or_default()),
+            ("a", true) => {
+                let href = links.pop().unwrap_or_default();
+                if !href.is_empty() && !href.starts_with('#') && !out.ends_with(&href) {
+                    out.push_str(&format!(" ({href})"));
+                }
+            }
+            ("img", _) => {
+                if let Some(alt) = attribute(tag, "alt").filter(|a| !a.is_empty()) {
+                    out.push_str(&format!("[{alt}]"));
+                }
+            }
+            ("pre", false) => {
+                pre += 1;
+                paragraph(&mut out);
+            }
+            ("pre", true) => {
+                pre = pre.saturating_sub(1);
+                paragraph(&mut out);
+            }
+            (name, _) if BLOCK_TAGS.contains(&name) => paragraph(&mut out),
+            _ => {}
+        }
+    }
+    push_text(&mut out, rest, pre > 0);
+    // trailing spaces and more than one empty line in a row
+    let mut text = String::new();
+    let mut empty = 0;
+    for line in out.lines().map(str::trim_end) {
+        empty = if line.trim().is_empty() { empty + 1 } else { 0 };
+        if empty < 2 {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    text.trim().to_string() + "\n"
+}
+
+fn push_text(out: &mut String, text: &str, keep_whitespace: bool) {
+    let text = decode_entities(text);
+    if keep_whitespace {
+        out.push_str(&text);
+        return;
+    }
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !out.is_empty() && !out.ends_with([' ', '\n', '\t']) {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+fn new_line(out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+fn paragraph(out: &mut String) {
+    new_line(out);
+    if !out.is_empty() && !out.ends_with("\n\n") {
+        out.push('\n');
+    }
+}
+
+// Value of a tag attribute, quoted or not
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name) {
+        let i = from + i;
+        from = i + name.len();
+        let before_ok = i > 0 && lower.as_bytes()[i - 1].is_ascii_whitespace();
+        let after = lower[from..].trim_start();
+        if !before_ok || !after.starts_with('=') {
+            continue;
+        }
+        let value = tag[tag.len() - after.len() + 1..].trim_start();
+        let value = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => value[1..].split(q).next().unwrap_or_default(),
+            _ => value.split_whitespace().next().unwrap_or_default(),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest.find(';').filter(|&e| e <= 10).and_then(|e| {
+            let c = match &rest[1..e] {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                n if n.starts_with("#x") || n.starts_with("#X") => u32::from_str_radix(&n[2..], 16)
+                    .ok()
+                    .and_then(char::from_u32),
+                n if n.starts_with('#') => n[1..].parse().ok().and_then(char::from_u32),
+                _ => None,
+            };
+            c.map(|c| (c, e))
+        });
+        match decoded {
+            Some((c, e)) => {
+                out.push(c);
+                rest = &rest[e + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities() {
+        assert_eq!(
+            to_text("<p>Fish &amp; chips &lt;3 &quot;x&quot; &#233;&#xE9; &bogus; &amp</p>"),
+            "Fish & chips <3 \"x\" éé &bogus; &amp\n"
+        );
+    }
+
+    #[test]
+    fn links_and_images() {
+        let html = "<p>See <a href=\"https://example.com/r?a=1&amp;b=2\">the report</a>, \
+                    <a href='#top'>top</a> or <a href=https://example.com>https://example.com</a>\
+                    <img src=\"cid:chart.png\" alt=\"Chart\"></p>";
+        assert_eq!(
+            to_text(html),
+            "See the report (https://example.com/r?a=1&b=2), top or https://example.com[Chart]\n"
+        );
+    }
+
+    #[test]
+    fn lists_and_blocks() {
+        let html = "<h1>Title</h1><ul><li>one</li><li>two\n  words</li></ul>\
+                    <table><tr><td>a</td><td>b</td></tr></table>line<br>break";
+        assert_eq!(
+            to_text(html),
+            "Title\n\n* one\n* two words\n\na\tb\n\nline\nbreak\n"
+        );
+    }
+
+    #[test]
+    fn head_script_and_comments_dropped() {
+        let html = "<html><HEAD><title>T</title><style>p { color: red }</style></HEAD>\
+                    <body><!-- a > b --><script>if (a < b) { x() }</script>\
+                    <p>Body</p><pre>  keep\n    spacing</pre></body></html>";
+        assert_eq!(to_text(html), "Body\n\n  keep\n    spacing\n");
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::Path;
+
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+// File signatures at offset 0, checked in order
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"BM", "image/bmp"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"), // empty archive
+    (b"\x1f\x8b", "application/gzip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", "application/msword"), // OLE2, old Office formats
+    (b"ID3", "audio/mpeg"),
+    (b"BEGIN:VCALENDAR", "text/calendar"),
+];
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("css", "text/css"),
+    ("md", "text/markdown"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("eml", "message/rfc822"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("doc", "application/msword"),
+    ("xls", "application/vnd.ms-excel"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+];
+
+/// Content type from the file extension, None when unknown
+pub fn from_extension(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Content type from the leading bytes of the data, None when unknown
+pub fn from_magic(data: &[u8]) -> Option<&'static str> {
+    if data.len() >= 12 && &data[..4] == b"RIFF" {
+        return match &data[8..12] {
+            b"WEBP" => Some("image/webp"),
+            b"WAVE" => Some("audio/wav"),
+            _ => None,
+        };
+    }
+    MAGIC
+        .iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Guess the content type of an attachment. The extension wins when known as
+/// signatures are ambiguous, .docx .xlsx .odt and .jar are all zip archives.
+/// Unknown data that is valid UTF-8 without control characters is sent as text.
+pub fn guess(filename: &str, data: &[u8]) -> &'static str {
+    if let Some(content_type) = from_extension(filename).or_else(|| from_magic(data)) {
+        return content_type;
+    }
+    // data may be just the start of a file, a character cut off at the end is fine
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return DEFAULT_TYPE,
+    };
+    match text
+        .chars()
+        .any(|c| c.is_control() && !"\t\r\n\x0c".contains(c))
+    {
+        true => DEFAULT_TYPE,
+        false => "text/plain",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(guess("scan", b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(guess("logo", b"\x89PNG\r\n\x1a\n\0\0"), "image/png");
+        assert_eq!(guess("photo", b"\xff\xd8\xff\xe0"), "image/jpeg");
+        assert_eq!(guess("image", b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(guess("sound", b"RIFF\0\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(from_magic(b"RIFF\0\0\0\0AVI LIST"), None);
+        assert_eq!(guess("archive", b"PK\x03\x04\x14\0"), "application/zip");
+    }
+
+    #[test]
+    fn extension_wins_over_magic() {
+        let zip = b"PK\x03\x04\x14\0";
+        assert_eq!(
+            guess("report.DOCX", zip),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        );
+        assert_eq!(guess("data.csv", b"a,b\n1,2\n"), "text/csv");
+        assert_eq!(guess("/tmp/dir.d/archive.tar.gz", b""), "application/gzip");
+        assert_eq!(from_extension("README"), None);
+    }
+
+    #[test]
+    fn text_fallback() {
+        assert_eq!(guess("notes", b"line one\r\n\tline two\n"), "text/plain");
+        assert_eq!(guess("notes", "Grüße".as_bytes()), "text/plain");
+        // the start of a file may end in the middle of a character
+        assert_eq!(guess("notes", &"Grüße".as_bytes()[..3]), "text/plain");
+        assert_eq!(guess("blob", b"\xff\xfe\x00x"), DEFAULT_TYPE);
+        assert_eq!(guess("blob", b"text with \x01 control"), DEFAULT_TYPE);
+        assert_eq!(guess("empty", b""), "text/plain");
+    }
+}
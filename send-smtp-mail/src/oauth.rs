@@ -0,0 +1,364 @@
+use crate::secret::Secret;
+use std::future::Future;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use zeroize::Zeroizing;
+
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Secret>> + Send + 'a>>;
+
+/// Source of OAuth 2.0 access tokens for XOAUTH2 / OAUTHBEARER
+pub trait TokenProvider: Send {
+    fn access_token(&mut self) -> TokenFuture<'_>;
+}
+
+/// A fixed access token e.g. from `smtp_oauth_token`, the caller is responsible for refreshing it
+pub struct StaticToken(pub Secret);
+
+impl TokenProvider for StaticToken {
+    fn access_token(&mut self) -> TokenFuture<'_> {
+        let token = self.0.clone();
+        Box::pin(async move { Ok(token) })
+    }
+}
+
+/// Exchange a long lived refresh token for an access token (RFC 6749 section 6).
+/// Works with the Google and Microsoft identity platform token endpoints.
+pub struct RefreshTokenProvider {
+    pub token_url: String, // e.g. https://oauth2.googleapis.com/token
+    pub client_id: String,
+    pub client_secret: Option<Secret>,
+    pub refresh_token: Secret,
+    pub scope: Option<String>, // Microsoft wants https://outlook.office.com/SMTP.Send
+    cached: Option<(Secret, Instant)>, // access token and expiry
+}
+
+impl RefreshTokenProvider {
+    pub fn new(token_url: &str, client_id: &str, refresh_token: Secret) -> Self {
+        RefreshTokenProvider {
+            token_url: token_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: None,
+            refresh_token,
+            scope: None,
+            cached: None,
+        }
+    }
+
+    async fn refresh(&mut self) -> io::Result<Secret> {
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("client_id", self.client_id.as_str()),
+            ("refresh_token", self.refresh_token.expose()),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.expose()));
+        }
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let body = Zeroizing::new(
+            form.iter()
+                .map(|(k, v)| format!("{}={}", k, url_encode(v)))
+                .collect::<Vec<String>>()
+                .join("&"),
+        );
+        log::info!("Requesting OAuth access token from {}", self.token_url);
+        let (status, response) = http_post_form(&self.token_url, &body).await?;
+        let response = Zeroizing::new(response);
+        let json: serde_json::Value = serde_json::from_slice(&response).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Token endpoint returned invalid JSON (HTTP {status}): {e}"),
+            )
+        })?;
+        let Some(token) = json["access_token"].as_str() else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Token endpoint HTTP {status} error: {} {}",
+                    json["error"].as_str().unwrap_or("unknown"),
+                    json["error_description"].as_str().unwrap_or_default()
+                ),
+            ));
+        };
+        let expires_in = json["expires_in"].as_u64().unwrap_or(3600);
+        log::info!("OAuth access token received, expires in {expires_in}s");
+        // refresh a minute early so the token does not expire mid session
+        let expiry = Instant::now() + Duration::from_secs(expires_in.saturating_sub(60));
+        let token = Secret::new(token.to_string());
+        self.cached = Some((token.clone(), expiry));
+        Ok(token)
+    }
+}
+
+impl TokenProvider for RefreshTokenProvider {
+    fn access_token(&mut self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            match &self.cached {
+                Some((token, expiry)) if Instant::now() < *expiry => Ok(token.clone()),
+                _ => self.refresh().await,
+            }
+        })
+    }
+}
+
+/// Describe the base64 decoded JSON error sent in a 334 reply after a rejected token,
+/// e.g. {"status":"401","schemes":"bearer","scope":"https://mail.google.com/"}
+pub fn describe_error(challenge: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(challenge) {
+        Ok(json) => format!(
+            "status={} schemes={} scope={}",
+            json["status"].as_str().unwrap_or("?"),
+            json["schemes"].as_str().unwrap_or("?"),
+            json["scope"].as_str().unwrap_or("?")
+        ),
+        Err(_) => String::from_utf8_lossy(challenge).to_string(),
+    }
+}
+
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Minimal HTTP/1.1 POST, http:// is accepted so a local mock token endpoint can be used
+async fn http_post_form(url: &str, body: &str) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{msg}: {url}"));
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(invalid("Token URL must start with http:// or https://"));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid("Invalid port"))?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    // the body holds the refresh token and client secret, the response the access token
+    let request = Zeroizing::new(format!(
+        "POST {path} HTTP/1.1\r\n\
+        Host: {authority}\r\n\
+        Content-Type: application/x-www-form-urlencoded\r\n\
+        Content-Length: {}\r\n\
+        Accept: application/json\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    ));
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not resolve host"))?;
+    let tcp = TcpStream::connect(addr).await?;
+    let raw = Zeroizing::new(if tls {
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            })
+            .with_no_client_auth();
+        let domain = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|_| invalid("Invalid hostname"))?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(domain, tcp)
+            .await?;
+        exchange(stream, request.as_bytes()).await?
+    } else {
+        exchange(tcp, request.as_bytes()).await?
+    });
+    parse_http_response(&raw)
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response).await {
+        Ok(_) => Ok(response),
+        // Some servers close without TLS close_notify, the body is complete anyway
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_http_response(raw: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response");
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&raw[..header_end]);
+    let body = &raw[header_end + 4..];
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let chunked = head.lines().any(|l| {
+        let l = l.to_ascii_lowercase();
+        l.starts_with("transfer-encoding:") && l.contains("chunked")
+    });
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+    // Decode chunked transfer encoding: <hex size>\r\n<data>\r\n ... 0\r\n\r\n
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size_line = String::from_utf8_lossy(&rest[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| invalid())?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok((status, decoded));
+        }
+        if rest.len() < size {
+            return Err(invalid());
+        }
+        decoded.extend_from_slice(&rest[..size]);
+        rest = rest.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    // Token endpoint answering one request with `response`, returns the URL and
+    // a handle to the request it received
+    async fn mock_endpoint(response: String) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .map_or(0, |l| l.parse().unwrap());
+                    if n == 0 || body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn provider(url: &str) -> RefreshTokenProvider {
+        let mut provider =
+            RefreshTokenProvider::new(url, "client-1", Secret::new("1//refresh".into()));
+        provider.client_secret = Some(Secret::new("s&cret".into()));
+        provider
+    }
+
+    #[tokio::test]
+    async fn refresh_token_plain_json() {
+        let json = r#"{"access_token":"ya29.token","expires_in":3599,"token_type":"Bearer"}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{json}",
+            json.len()
+        );
+        let (url, request) = mock_endpoint(response).await;
+        let mut provider = provider(&url);
+        assert_eq!(
+            provider.access_token().await.unwrap().expose(),
+            "ya29.token"
+        );
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /token HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            "grant_type=refresh_token&client_id=client-1&refresh_token=1%2F%2Frefresh&client_secret=s%26cret"
+        ));
+        // cached, the endpoint is gone and would refuse a second request
+        assert_eq!(
+            provider.access_token().await.unwrap().expose(),
+            "ya29.token"
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_token_chunked_json() {
+        let response = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+            Transfer-Encoding: chunked\r\n\r\n\
+            10\r\n{\"access_token\":\r\n\
+            10;ext=1\r\n\"chunked.token\"}\r\n\
+            0\r\n\r\n";
+        let (url, _) = mock_endpoint(response.to_string()).await;
+        let token = provider(&url).access_token().await.unwrap();
+        assert_eq!(token.expose(), "chunked.token");
+    }
+
+    #[tokio::test]
+    async fn refresh_token_error_json() {
+        let json =
+            r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#;
+        let response = format!(
+            "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{json}",
+            json.len()
+        );
+        let (url, _) = mock_endpoint(response).await;
+        let err = provider(&url).access_token().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            err.to_string(),
+            "Token endpoint HTTP 400 error: invalid_grant Token has been expired or revoked."
+        );
+    }
+
+    #[test]
+    fn invalid_http_response() {
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_http_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn describe_error_json_and_text() {
+        let json = br#"{"status":"401","schemes":"bearer","scope":"https://mail.google.com/"}"#;
+        assert_eq!(
+            describe_error(json),
+            "status=401 schemes=bearer scope=https://mail.google.com/"
+        );
+        assert_eq!(
+            describe_error(br#"{"status":"400"}"#),
+            "status=400 schemes=? scope=?"
+        );
+        assert_eq!(describe_error(b"not json"), "not json");
+    }
+}
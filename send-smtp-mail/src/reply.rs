@@ -0,0 +1,182 @@
+use std::io;
+
+/// A complete SMTP reply as defined in RFC 5321 section 4.2.
+///
+/// Multi-line replies use `NNN-text` for every line except the last, which
+/// uses `NNN text` (or just `NNN`). All lines must carry the same code.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Reply {
+    pub code: u16,
+    // RFC 3463 enhanced status code e.g. "2.1.0", when the server sends one
+    pub enhanced_status: Option<String>,
+    // Text of each line with the code and separator removed
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// Parse one complete reply, the input must end with the final `NNN ` line.
+    pub fn parse(input: &str) -> io::Result<Reply> {
+        let mut code = None;
+        let mut lines = Vec::new();
+        for line in input.lines() {
+            let (line_code, is_last, text) = parse_line(line)?;
+            match code {
+                None => code = Some(line_code),
+                Some(c) if c != line_code => {
+                    return Err(invalid(format!(
+                        "Reply code changed from {c} to {line_code} in multi-line reply"
+                    )))
+                }
+                Some(_) => {}
+            }
+            lines.push(text.to_string());
+            if is_last {
+                let code = line_code;
+                let enhanced_status = parse_enhanced_status(code, &lines[0]);
+                return Ok(Reply {
+                    code,
+                    enhanced_status,
+                    lines,
+                });
+            }
+        }
+        Err(invalid(format!("Incomplete SMTP reply: {:?}", input)))
+    }
+
+    /// First digit of the code, 2 = ok, 3 = intermediate, 4 = transient, 5 = permanent
+    pub fn class(&self) -> u16 {
+        self.code / 100
+    }
+    pub fn is_positive(&self) -> bool {
+        self.class() == 2
+    }
+    pub fn is_intermediate(&self) -> bool {
+        self.class() == 3
+    }
+    pub fn is_transient_error(&self) -> bool {
+        self.class() == 4
+    }
+    pub fn is_permanent_error(&self) -> bool {
+        self.class() == 5
+    }
+
+    /// Text of the reply, lines joined with a space and the enhanced status removed
+    pub fn message(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| match &self.enhanced_status {
+                Some(status) => line
+                    .strip_prefix(status.as_str())
+                    .map(|l| l.trim_start())
+                    .unwrap_or(line),
+                None => line,
+            })
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+impl std::fmt::Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" | "))
+    }
+}
+
+/// Split a single reply line into (code, is_last_line, text)
+pub fn parse_line(line: &str) -> io::Result<(u16, bool, &str)> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let bytes = line.as_bytes();
+    if bytes.len() < 3 || !bytes[..3].iter().all(u8::is_ascii_digit) {
+        return Err(invalid(format!("Invalid SMTP reply line: {:?}", line)));
+    }
+    let code: u16 = line[..3].parse().expect("three ascii digits");
+    if !(200..600).contains(&code) {
+        return Err(invalid(format!("SMTP reply code out of range: {:?}", line)));
+    }
+    match bytes.get(3) {
+        None => Ok((code, true, "")),
+        Some(b' ') => Ok((code, true, &line[4..])),
+        Some(b'-') => Ok((code, false, &line[4..])),
+        Some(_) => Err(invalid(format!("Invalid SMTP reply separator: {:?}", line))),
+    }
+}
+
+// Enhanced status codes start with the same class digit as the reply e.g. "250 2.1.0 Sender OK"
+fn parse_enhanced_status(code: u16, text: &str) -> Option<String> {
+    let candidate = text.split_whitespace().next()?;
+    let parts: Vec<&str> = candidate.split('.').collect();
+    let valid = parts.len() == 3
+        && parts[0] == (code / 100).to_string()
+        && parts[1..]
+            .iter()
+            .all(|p| !p.is_empty() && p.len() <= 3 && p.bytes().all(|b| b.is_ascii_digit()));
+    valid.then(|| candidate.to_string())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_line_reply() {
+        let reply =
+            Reply::parse("250-mail.example.com Hello\r\n250-SIZE 35882577\r\n250 8BITMIME\r\n")
+                .unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(
+            reply.lines,
+            ["mail.example.com Hello", "SIZE 35882577", "8BITMIME"]
+        );
+        assert_eq!(reply.enhanced_status, None);
+        assert!(reply.is_positive());
+    }
+
+    #[test]
+    fn code_only_line() {
+        let reply = Reply::parse("250\r\n").unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(reply.lines, [""]);
+        assert_eq!(parse_line("250").unwrap(), (250, true, ""));
+    }
+
+    #[test]
+    fn incomplete_reply() {
+        assert!(Reply::parse("250-first line\r\n").is_err());
+    }
+
+    #[test]
+    fn code_mismatch_between_lines() {
+        let err = Reply::parse("250-first\r\n251 second\r\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("250 to 251"));
+    }
+
+    #[test]
+    fn bad_separator_and_code() {
+        assert!(parse_line("250_ok").is_err());
+        assert!(parse_line("25 ok").is_err());
+        assert!(parse_line("abc ok").is_err());
+        assert!(parse_line("199 out of range").is_err());
+        assert_eq!(parse_line("354-go on\r\n").unwrap(), (354, false, "go on"));
+    }
+
+    #[test]
+    fn enhanced_status() {
+        let reply = Reply::parse("550 5.1.1 <nobody@example.com>: user unknown\r\n").unwrap();
+        assert_eq!(reply.enhanced_status.as_deref(), Some("5.1.1"));
+        assert_eq!(reply.message(), "<nobody@example.com>: user unknown");
+        assert!(reply.is_permanent_error());
+        // the class digit must match the reply code
+        assert_eq!(parse_enhanced_status(250, "5.1.1 text"), None);
+        assert_eq!(parse_enhanced_status(250, "2.1 text"), None);
+        assert_eq!(parse_enhanced_status(250, "2.1.1234 text"), None);
+        assert_eq!(
+            parse_enhanced_status(451, "4.7.0 try later"),
+            Some("4.7.0".into())
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// Passwords and OAuth tokens. A secret setting, e.g. smtp_password, can be given
+// in one of four ways:
+//   smtp_password=...                 the value itself
+//   smtp_password_file=/run/secrets/smtp    Docker / Kubernetes secret file
+//   smtp_password_command="pass show smtp"  first line of the command output
+//   smtp_password_keyring="service smtp user ops"  Secret Service lookup with secret-tool
+// The value is kept in a Secret, wiped from memory when dropped and never logged.
+
+use crate::config::Config;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+/// Shown in logs instead of a secret
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Clone)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The secret from `key` or its _file, _command or _keyring variant, None when none is set
+    pub fn from_config(config: &Config, key: &str) -> io::Result<Option<Secret>> {
+        let sources: Vec<(&str, String)> = ["", "_file", "_command", "_keyring"]
+            .into_iter()
+            .filter_map(|suffix| config.var(&format!("{key}{suffix}")).map(|v| (suffix, v)))
+            .collect();
+        let (suffix, value) = match &sources[..] {
+            [] => return Ok(None),
+            [source] => source,
+            _ => {
+                return Err(config.error(
+                    key,
+                    format!("set only one of {key}, {key}_file, {key}_command and {key}_keyring"),
+                ))
+            }
+        };
+        let source = format!("{key}{suffix}");
+        let secret = match *suffix {
+            "" => Ok(Secret::new(value.clone())),
+            "_file" => read_file(value),
+            "_command" => run(Command::new("sh").args(["-c", value])),
+            _ => keyring(value),
+        }
+        .map_err(|e| config.error(&source, e))?;
+        if secret.expose().is_empty() {
+            return Err(config.error(&source, "empty secret"));
+        }
+        Ok(Some(secret))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+// the whole file without the trailing newline editors and echo add
+fn read_file(path: &str) -> io::Result<Secret> {
+    let mut text = Zeroizing::new(
+        fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to read {path}: {e}")))?,
+    );
+    let len = text.trim_end_matches(['\r', '\n']).len();
+    text.truncate(len);
+    Ok(Secret::new(text.to_string()))
+}
+
+// "service smtp user ops" -> secret-tool lookup service smtp user ops
+fn keyring(attributes: &str) -> io::Result<Secret> {
+    let attributes: Vec<&str> = attributes.split_whitespace().collect();
+    if attributes.is_empty() || !attributes.len().is_multiple_of(2) {
+        return Err(invalid(format!(
+            "needs attribute value pairs, e.g. \"service smtp user ops\", got {attributes:?}"
+        )));
+    }
+    run(Command::new("secret-tool").arg("lookup").args(&attributes))
+}
+
+// first line of stdout, like pass prints the password before any notes.
+// stdin may be the message in sendmail mode, prompts from gpg or op use the
+// terminal and stderr is passed through
+fn run(command: &mut Command) -> io::Result<Secret> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to run {program}: {e}")))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "command failed: {}",
+            output.status
+        )));
+    }
+    let line = stdout.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("printed a secret that is not UTF-8".to_string()))?;
+    Ok(Secret::new(line.to_string()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -1,97 +1,137 @@
-use base64::engine::general_purpose::STANDARD as b64;
-use base64::Engine; // trait
-
+use crate::capabilities::ServerCapabilities;
+use crate::reply::Reply;
 use crate::stream;
 
 #[derive(Debug, PartialEq)]
 pub enum Event {
     NoEvent,
     Connect,
-    Received220(String),
-    Received250(String),
-    Received250StartTls(String),
-    Received250StartTlsAuth(String),
-    Received334Username,
-    Received334Password,
-    AuthSuccess(String),
-    Received250Queued(String),
-    Received250SenderOk(String),
-    Received250RecipientOk(String),
-    Received354MailInput(String),
-    Received4xx(String),
-    Received5xx(String),
+    Received220(Reply),
+    Received250(Reply),
+    Received250Ehlo(Reply), // capabilities are stored on the connection
+    Received334(Reply),     // AUTH challenge
+    AuthSuccess(Reply),
+    Received250Queued(Reply),
+    Received250SenderOk(Reply),
+    Received250RecipientOk(Reply),
+    RecipientRejected(Reply),
+    Received354MailInput(Reply),
+    Received250Chunk(Reply), // BDAT chunk accepted, send the next one
+    Received4xx(Reply),
+    Received5xx(Reply),
     Stop,
     Timeout,
     Complete,
 }
+
+/// The last command written to the server, a reply is interpreted in its context
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command {
+    None, // Nothing sent yet, waiting for the greeting
+    Ehlo,
+    StartTls,
+    Auth,
+    AuthResponse, // base64 line sent in answer to a 334 challenge
+    MailFrom,
+    RcptTo,
+    Data,
+    DataEnd,  // message body terminated with <CRLF>.<CRLF>
+    Bdat,     // BDAT chunk, more follow
+    BdatLast, // final BDAT chunk, the reply is the delivery result
+    Quit,
+    Other,
+}
+impl Command {
+    /// Classify a command line by its verb
+    pub fn from_line(line: &str) -> Command {
+        let upper = line.to_ascii_uppercase();
+        let verb = upper.split([' ', ':']).next().unwrap_or_default();
+        match verb {
+            "EHLO" | "HELO" => Command::Ehlo,
+            "STARTTLS" => Command::StartTls,
+            "AUTH" => Command::Auth,
+            "MAIL" => Command::MailFrom,
+            "RCPT" => Command::RcptTo,
+            "DATA" => Command::Data,
+            "BDAT" if upper.ends_with(" LAST") => Command::BdatLast,
+            "BDAT" => Command::Bdat,
+            "QUIT" => Command::Quit,
+            _ => Command::Other,
+        }
+    }
+}
+
 pub async fn get_event(smtp_connection: &mut stream::SmtpConnection) -> Event {
-    // Placeholder for actual event logic
-    // This is where you would implement the logic to determine the event based on the state of the connection
-    let input = smtp_connection.read().await;
-    log::info!("Input read debug: {:?}", input);
-    match input {
-        Ok(input) => {
-            log::debug!("Received data from SMTP server: {}", input);
-            for line in input.lines() {
-                if line.starts_with("220") {
-                    log::info!("starts_with 220: {}", line);
-                    return Event::Received220(line.to_string());
-                };
-                if line.starts_with("250") {
-                    // Check if the line contains "STARTTLS"
-                    if input.contains("STARTTLS") {
-                        log::info!("STARTTLS supported: {}", line);
-                        return Event::Received250StartTls(line.to_string());
-                    }
-                    if input.contains("AUTH") {
-                        log::info!("AUTH supported: {}", line);
-                        return Event::Received250StartTlsAuth(line.to_string());
-                    }
-                    if input.contains("Sender OK") {
-                        log::info!("starts_with 250: {}", line);
-                        return Event::Received250SenderOk(line.to_string());
-                    }
-                    if input.contains("Recipient OK") {
-                        log::info!("starts_with 250: {}", line);
-                        return Event::Received250RecipientOk(line.to_string());
-                    }
-                    if input.contains("Queued") {
-                        log::info!("starts_with 250: {}", line);
-                        return Event::Received250Queued(line.to_string());
-                    }
-                    log::info!("starts_with 250: {}", line);
-                    return Event::Received250(line.to_string());
-                };
-                if line.starts_with(&format!("334 {}", b64.encode("Username:"))) {
-                    log::info!("starts_with 334: {}", line);
-                    return Event::Received334Username;
-                };
-                if line.starts_with(&format!("334 {}", b64.encode("Password:"))) {
-                    log::info!("starts_with 334: {}", line);
-                    return Event::Received334Password;
-                };
-                if line.starts_with("235") {
-                    log::info!("starts_with 235: {}", line);
-                    return Event::AuthSuccess(line.to_string());
-                };
-                if line.starts_with("354") {
-                    // "354 Start mail input; end with <CRLF>.<CRLF>
-                    log::info!("starts_with 354: {}", line);
-                    return Event::Received354MailInput(line.to_string());
-                };
-                if line.starts_with("4") {
-                    log::info!("starts_with 4xx: {}", line);
-                    return Event::Received4xx(line.to_string());
-                };
-                if line.starts_with("5") {
-                    return Event::Received5xx(line.to_string());
-                }
+    match smtp_connection.read_reply().await {
+        Ok(reply) => {
+            log::debug!("Received reply from SMTP server: {:?}", reply);
+            if reply.code == 250 && smtp_connection.last_command == Command::Ehlo {
+                smtp_connection.capabilities = ServerCapabilities::from_ehlo(&reply);
+                log::info!("Server capabilities: {:?}", smtp_connection.capabilities);
             }
-            return Event::NoEvent;
+            reply_to_event(reply, smtp_connection)
         }
         Err(e) => {
             log::error!("Error reading from SMTP server: {:?}", e);
-            return Event::Stop;
+            Event::Stop
         }
     }
 }
+
+/// Map a reply to an event using its numeric code and the command it answers
+pub fn reply_to_event(reply: Reply, smtp_connection: &stream::SmtpConnection) -> Event {
+    let last_command = smtp_connection.last_command;
+    log::info!("Reply {} to {:?}: {}", reply.code, last_command, reply);
+    match (reply.code, last_command) {
+        (220, _) => Event::Received220(reply),
+        (221, _) => Event::Complete,
+        (235, _) => Event::AuthSuccess(reply),
+        (250, Command::Ehlo) => Event::Received250Ehlo(reply),
+        (250, Command::MailFrom) => Event::Received250SenderOk(reply),
+        (250 | 251, Command::RcptTo) => Event::Received250RecipientOk(reply),
+        (250, Command::DataEnd | Command::BdatLast) => Event::Received250Queued(reply),
+        (250, Command::Bdat) => Event::Received250Chunk(reply),
+        (334, _) => Event::Received334(reply),
+        // the server is closing the connection, not a verdict on the recipient
+        (421, _) => Event::Received4xx(reply),
+        (400..=599, Command::RcptTo) => Event::RecipientRejected(reply),
+        (354, _) => Event::Received354MailInput(reply),
+        (200..=299, _) => Event::Received250(reply),
+        (400..=499, _) => Event::Received4xx(reply),
+        (500..=599, _) => Event::Received5xx(reply),
+        _ => {
+            log::error!("Unexpected reply: {}", reply);
+            Event::NoEvent
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Mailbox;
+
+    fn after(command: Command, line: &str) -> Event {
+        let from = Mailbox::parse("a@example.com").unwrap();
+        let mut connection =
+            stream::SmtpConnection::new("localhost", 25, None, None, from, vec![], "");
+        connection.last_command = command;
+        reply_to_event(Reply::parse(line).unwrap(), &connection)
+    }
+
+    #[test]
+    fn rcpt_to_replies() {
+        assert!(matches!(
+            after(Command::RcptTo, "250 2.1.5 ok\r\n"),
+            Event::Received250RecipientOk(_)
+        ));
+        assert!(matches!(
+            after(Command::RcptTo, "550 5.1.1 unknown\r\n"),
+            Event::RecipientRejected(_)
+        ));
+        assert!(matches!(
+            after(Command::RcptTo, "421 4.3.2 shutting down\r\n"),
+            Event::Received4xx(_)
+        ));
+    }
+}
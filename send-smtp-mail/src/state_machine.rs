@@ -1,22 +1,32 @@
-use crate::state_events::Event;
+use crate::address;
+use crate::auth;
+use crate::config::Config;
+use crate::data;
+use crate::mime;
+use crate::oauth;
+use crate::reply::Reply;
+use crate::secret::{self, Secret};
+use crate::state_events::{self, Command, Event};
+use crate::tls;
 mod send_body;
 use crate::stream; // Replace 'some_crate' with the actual crate or module where Stream is defined
-use base64::engine::general_purpose::STANDARD as b64;
-use base64::Engine; // trait
-use dotenv::dotenv;
-use std::env;
-use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum State {
     Start,
     ConnectingTcp,
+    ConnectingTls, // implicit TLS, handshake done, waiting for the greeting
     ConnectedTcpHelloSent,
-    ConnectedTcp,
     ConnectedTcpStartTls,
     ConnectedTls,
+    Authenticating,
     SendingMailHeaders,
     SendingMailData,
+    SendingMailChunks, // BDAT, waiting for the reply to a chunk
     MailSent,
     Finished,
     Failed,
@@ -24,6 +34,8 @@ pub enum State {
 pub struct StateMachine {
     pub state: State,
     pub smtp_connection: stream::SmtpConnection,
+    authenticator: Option<auth::Authenticator>, // AUTH exchange in progress
+    error_reply: Option<Reply>,                 // 4xx or 5xx reply to the last command
 }
 impl StateMachine {
     pub async fn handle_event(&mut self, event: Event) {
@@ -32,13 +44,26 @@ impl StateMachine {
             self.state,
             event
         );
+        self.error_reply = match &event {
+            Event::Received4xx(reply) | Event::Received5xx(reply) => Some(reply.clone()),
+            _ => None,
+        };
         self.state = match (&self.state, event) {
+            // RFC 5321 4.2.2: 421 may answer any command, nothing more is accepted
+            (_, Event::Received4xx(reply)) if reply.code == 421 => {
+                log::error!("Server is closing the connection: {}", reply);
+                State::Failed
+            }
             (State::Start, Event::Connect) => {
                 match self
                     .smtp_connection
                     .connect_to_server() // Call the function to connect to the server
                     .await
                 {
+                    Ok(_) if self.smtp_connection.is_tls() => {
+                        log::info!("Implicit TLS connected, waiting for greeting");
+                        State::ConnectingTls
+                    }
                     Ok(_) => {
                         log::info!("Transitioning from Connect to ConnectedReady");
                         State::ConnectingTcp
@@ -59,23 +84,20 @@ impl StateMachine {
                 )
                 .await
             }
-            (State::ConnectedTcpHelloSent, Event::Received250(_msg)) => {
-                log::info!("EHLO accepted, transitioning to ConnectedTcp");
-                State::ConnectedTcp
-            }
-            (
-                State::ConnectedTcp | State::ConnectedTcpHelloSent,
-                Event::Received250StartTls(_msg),
-            ) => {
-                log::info!("Sending STARTTLS command");
+            (State::ConnectingTls, Event::Received220(_msg)) => {
+                log::info!("Greeting received over implicit TLS, send EHLO");
                 self.write_and_get_next_state(
-                    "STARTTLS",
-                    State::ConnectedTcpStartTls,
-                    "STARTTLS sent successfully",
+                    "EHLO rustclient",
+                    State::ConnectedTls,
+                    "EHLO over TLS sent successfully",
                     State::Failed,
                 )
                 .await
             }
+            (State::ConnectedTcpHelloSent | State::ConnectedTls, Event::Received250Ehlo(_msg)) => {
+                log::info!("EHLO accepted");
+                self.after_ehlo().await
+            }
             (State::ConnectedTcpStartTls, Event::Received220(_msg)) => {
                 log::info!("STARTTLS accepted server ready to transition to Tls");
                 match self.smtp_connection.switch_to_tls().await {
@@ -89,94 +111,72 @@ impl StateMachine {
                         .await
                     }
                     Err(e) => {
-                        log::error!("Failed to switch to TLS: {:?}", e);
+                        // The TCP stream is consumed by the handshake, no plaintext fallback
+                        log::error!(
+                            "TLS handshake with {} failed: {}",
+                            self.smtp_connection.host,
+                            e
+                        );
                         State::Failed
                     }
                 }
             }
-            (State::ConnectedTls, Event::Received250(_msg)) => {
-                log::info!("TLS 2nd EHLO accepted, starting AUTH");
-                self.write_and_get_next_state(
-                    "AUTH LOGIN",
-                    State::ConnectedTls,
-                    "AUTH LOGIN sent successfully",
-                    State::Failed,
-                )
-                .await
-            }
-            (State::ConnectedTls, Event::Received250StartTlsAuth(_msg)) => {
-                log::info!("Received request to proceed with AUTH");
-                // send "AUTH LOGIN"
-                self.write_and_get_next_state(
-                    "AUTH LOGIN",
-                    State::ConnectedTls,
-                    "AUTH LOGIN sent successfully",
-                    State::Failed,
-                )
-                .await
-            }
-            (State::ConnectedTls, Event::Received334Username) => {
-                log::info!("Received request for username");
-                // send username
-                if let Some(username) = self.smtp_connection.username.clone() {
-                    self.write_and_get_next_state(
-                        &b64.encode(&username),
-                        State::ConnectedTls,
-                        "Username sent successfully",
-                        State::Failed,
-                    )
-                    .await
+            (
+                State::ConnectedTcpStartTls,
+                Event::Received4xx(reply) | Event::Received5xx(reply),
+            ) => {
+                if self.smtp_connection.tls_policy == stream::TlsPolicy::Opportunistic {
+                    log::warn!(
+                        "Server refused STARTTLS ({}), continuing without TLS",
+                        reply
+                    );
+                    // treat as not offered so after_ehlo does not try again
+                    self.smtp_connection.capabilities.starttls = false;
+                    self.after_ehlo().await
                 } else {
-                    log::error!("Username not provided ?");
+                    log::error!("TLS is required but the server refused STARTTLS: {}", reply);
                     State::Failed
                 }
             }
-            (State::ConnectedTls, Event::Received334Password) => {
-                log::info!("Received request for password");
-                // send password
-                if let Some(password) = self.smtp_connection.password.clone() {
-                    self.write_and_get_next_state(
-                        &b64.encode(password),
-                        State::ConnectedTls,
-                        "Password sent successfully",
-                        State::Failed,
-                    )
-                    .await
-                } else {
-                    log::error!("Password not provided ?");
-                    State::Failed
+            (State::Authenticating, Event::Received334(reply)) => {
+                let challenge = reply.lines.first().cloned().unwrap_or_default();
+                let response = match self.authenticator.as_mut() {
+                    Some(authenticator) => authenticator.respond(&challenge),
+                    None => Err(std::io::Error::other("AUTH challenge without AUTH command")),
+                };
+                match response {
+                    Ok(response) => {
+                        self.write_and_get_next_state(
+                            response.expose(),
+                            State::Authenticating,
+                            "AUTH response sent successfully",
+                            State::Failed,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        log::error!("AUTH failed: {}", e);
+                        State::Failed
+                    }
                 }
             }
-            (State::ConnectedTls, Event::AuthSuccess(_)) => {
+            (State::Authenticating, Event::AuthSuccess(_)) => {
                 log::info!("AUTH successfull, ready to start sending MAIL FROM");
-                self.write_and_get_next_state(
-                    &format!("MAIL FROM:<{}>", self.smtp_connection.from),
-                    State::SendingMailHeaders,
-                    "MAIL FROM sent successfully",
-                    State::Failed,
-                )
-                .await
+                self.send_mail_from().await
             }
 
             (State::SendingMailHeaders, Event::Received250SenderOk(_msg)) => {
                 log::info!("MAIL FROM accepted, ready to send RCPT TO");
-                self.write_and_get_next_state(
-                    &format!("RCPT TO:<{}>", self.smtp_connection.to),
-                    State::SendingMailHeaders,
-                    "RCPT TO sent successfully",
-                    State::Failed,
-                )
-                .await
+                self.send_next_rcpt_or_data().await
             }
-            (State::SendingMailHeaders, Event::Received250RecipientOk(_msg)) => {
-                log::info!("RCPT TO accepted, request we start sending DATA");
-                self.write_and_get_next_state(
-                    "DATA",
-                    State::SendingMailData,
-                    "DATA sent successfully",
-                    State::Failed,
-                )
-                .await
+            (State::SendingMailHeaders, Event::Received250RecipientOk(reply)) => {
+                self.record_recipient_result(reply, true);
+                self.send_next_rcpt_or_data().await
+            }
+            (State::SendingMailHeaders, Event::RecipientRejected(reply)) => {
+                // A rejected recipient does not stop delivery to the others
+                self.record_recipient_result(reply, false);
+                self.send_next_rcpt_or_data().await
             }
 
             (State::SendingMailData, Event::Received354MailInput(_msg)) => {
@@ -184,11 +184,24 @@ impl StateMachine {
                 // Send the email body
                 send_body::send_body(&mut self.smtp_connection).await
             }
+            (State::SendingMailChunks, Event::Received250Chunk(_msg)) => {
+                send_body::send_chunk(&mut self.smtp_connection).await
+            }
             (State::MailSent, Event::Received250Queued(_msg)) => {
                 log::info!("Email sent successfully, transitioning to Finished");
                 self.write_and_get_next_state("QUIT", State::Finished, "QUIT", State::Failed)
                     .await
             }
+            (State::SendingMailHeaders, Event::Received5xx(reply))
+                if reply.code == 552 && self.smtp_connection.last_command == Command::MailFrom =>
+            {
+                log::error!(
+                    "Server refused the message size of {} bytes: {}",
+                    self.smtp_connection.message_size.unwrap_or_default(),
+                    reply
+                );
+                State::Failed
+            }
             (_, Event::Received5xx(_msg)) => {
                 log::error!("Received 5xx error, transitioning to Failed");
                 State::Failed
@@ -204,6 +217,227 @@ impl StateMachine {
         }
     }
 
+    // Decide the next step from what the server advertised in its EHLO reply
+    async fn after_ehlo(&mut self) -> State {
+        let connection = &self.smtp_connection;
+        if !connection.is_tls() {
+            let try_starttls = connection.security == stream::ConnectionSecurity::StartTls
+                && connection.tls_policy != stream::TlsPolicy::Disabled;
+            if try_starttls && connection.capabilities.starttls {
+                log::info!("Sending STARTTLS command");
+                return self
+                    .write_and_get_next_state(
+                        "STARTTLS",
+                        State::ConnectedTcpStartTls,
+                        "STARTTLS sent successfully",
+                        State::Failed,
+                    )
+                    .await;
+            }
+            if connection.tls_policy == stream::TlsPolicy::Required {
+                log::error!(
+                    "TLS is required but {} does not advertise STARTTLS, set smtp_tls_policy=opportunistic to send without TLS",
+                    connection.host
+                );
+                return State::Failed;
+            }
+            log::warn!("Continuing without TLS, the message is sent unencrypted");
+        }
+        if connection.capabilities.auth.is_empty() || connection.username.is_none() {
+            log::info!("Server does not advertise AUTH or no username set, skip to MAIL FROM");
+            return self.send_mail_from().await;
+        }
+        log::info!("Server advertises AUTH, proceed with AUTH");
+        self.start_auth().await
+    }
+
+    // Pick a mechanism from the EHLO AUTH list and send the AUTH command
+    async fn start_auth(&mut self) -> State {
+        if !self.smtp_connection.is_tls() && !self.smtp_connection.allow_plaintext_auth {
+            log::error!(
+                "Refusing to send credentials over an unencrypted connection, set smtp_allow_plaintext_auth=true to allow"
+            );
+            return State::Failed;
+        }
+        let Some(username) = self.smtp_connection.username.clone() else {
+            log::error!("Server requests AUTH but username not provided ?");
+            return State::Failed;
+        };
+        let Some(mechanism) = auth::Mechanism::select(
+            &self.smtp_connection.capabilities.auth,
+            self.smtp_connection.auth_mechanism,
+            self.smtp_connection.is_tls(),
+            self.smtp_connection.token_provider.is_some(),
+        ) else {
+            log::error!(
+                "No supported AUTH mechanism in server list {:?}",
+                self.smtp_connection.capabilities.auth
+            );
+            return State::Failed;
+        };
+        log::info!("Using AUTH {}", mechanism.name());
+        let secret = if mechanism.is_oauth() {
+            let Some(provider) = self.smtp_connection.token_provider.as_mut() else {
+                log::error!(
+                    "AUTH {} needs an OAuth token, none configured",
+                    mechanism.name()
+                );
+                return State::Failed;
+            };
+            match provider.access_token().await {
+                Ok(token) => token,
+                Err(e) => {
+                    log::error!("Failed to get OAuth access token: {}", e);
+                    return State::Failed;
+                }
+            }
+        } else if let Some(password) = self.smtp_connection.password.clone() {
+            password
+        } else {
+            log::error!("Server requests AUTH but password not provided ?");
+            return State::Failed;
+        };
+        let mut authenticator = auth::Authenticator::new(mechanism, &username, secret)
+            .with_server(&self.smtp_connection.host, self.smtp_connection.port);
+        let command = authenticator.initial_command();
+        self.authenticator = Some(authenticator);
+        self.write_and_get_next_state(
+            command.expose(),
+            State::Authenticating,
+            "AUTH sent successfully",
+            State::Failed,
+        )
+        .await
+    }
+
+    async fn send_mail_from(&mut self) -> State {
+        if let Err(e) = send_body::prepare_message(&mut self.smtp_connection) {
+            log::error!("Failed to build email: {}", e);
+            return State::Failed;
+        }
+        let connection = &self.smtp_connection;
+        let size = connection.message_size.unwrap_or_default();
+        let mut mail_from = format!("MAIL FROM:<{}>", connection.from.address());
+        // RFC 6152 and RFC 6531, declared only when the message needs them
+        let (eight_bit, smtputf8) = match &connection.message {
+            Some(message) => (message.is_8bit(), message.has_utf8_headers()),
+            None => (false, false),
+        };
+        let smtputf8 = smtputf8
+            || connection.from.is_utf8()
+            || connection
+                .envelope_recipients()
+                .iter()
+                .any(|r| !r.is_ascii());
+        if eight_bit && !connection.capabilities.eight_bit_mime {
+            log::error!(
+                "Message has 8bit content but {} does not support 8BITMIME",
+                connection.host
+            );
+            return State::Failed;
+        }
+        if smtputf8 && !connection.capabilities.smtputf8 {
+            log::error!(
+                "Message has UTF-8 addresses or headers but {} does not support SMTPUTF8",
+                connection.host
+            );
+            return State::Failed;
+        }
+        if eight_bit {
+            mail_from.push_str(" BODY=8BITMIME");
+        }
+        if smtputf8 {
+            mail_from.push_str(" SMTPUTF8");
+        }
+        // RFC 1870: SIZE=0 in EHLO means no fixed limit, the size is still declared
+        if let Some(limit) = connection.capabilities.size {
+            if limit > 0 && size > limit {
+                let message = format!(
+                    "Message is {} bytes ({:.2}Mb), {} accepts at most {} bytes ({:.2}Mb)",
+                    size,
+                    size as f64 / (1024.0 * 1024.0),
+                    connection.host,
+                    limit,
+                    limit as f64 / (1024.0 * 1024.0)
+                );
+                if connection.size_policy == stream::SizePolicy::Enforce {
+                    log::error!("{message}, not sending. Set smtp_size_policy=warn to try anyway");
+                    return State::Failed;
+                }
+                log::warn!("{message}, sending anyway as smtp_size_policy=warn");
+            }
+            mail_from.push_str(&format!(" SIZE={size}"));
+        }
+        log::info!("Message size {} bytes", size);
+        self.write_and_get_next_state(
+            &mail_from,
+            State::SendingMailHeaders,
+            "MAIL FROM sent successfully",
+            State::Failed,
+        )
+        .await
+    }
+
+    // Send RCPT TO for the next envelope recipient, or DATA once all have been sent
+    async fn send_next_rcpt_or_data(&mut self) -> State {
+        let recipients = self.smtp_connection.envelope_recipients();
+        let results = &self.smtp_connection.recipient_results;
+        if let Some(address) = recipients.get(results.len()) {
+            return self
+                .write_and_get_next_state(
+                    &format!("RCPT TO:<{}>", address),
+                    State::SendingMailHeaders,
+                    "RCPT TO sent successfully",
+                    State::Failed,
+                )
+                .await;
+        }
+        let accepted = results.iter().filter(|r| r.accepted).count();
+        log::info!(
+            "RCPT TO done, {} of {} recipients accepted",
+            accepted,
+            results.len()
+        );
+        if accepted == 0 {
+            log::error!("No recipient accepted by the server, not sending DATA");
+            // a 4xx rejection may pass on retry, the lowest code decides
+            self.error_reply = results
+                .iter()
+                .map(|r| r.reply.clone())
+                .min_by_key(|r| r.code);
+            return State::Failed;
+        }
+        // RFC 3030: BDAT replaces DATA, no 354 and no dot-stuffing
+        if self.smtp_connection.chunking && self.smtp_connection.capabilities.chunking {
+            return send_body::send_chunk(&mut self.smtp_connection).await;
+        }
+        self.write_and_get_next_state(
+            "DATA",
+            State::SendingMailData,
+            "DATA sent successfully",
+            State::Failed,
+        )
+        .await
+    }
+
+    fn record_recipient_result(&mut self, reply: Reply, accepted: bool) {
+        let recipients = self.smtp_connection.envelope_recipients();
+        let index = self.smtp_connection.recipient_results.len();
+        let address = recipients.get(index).cloned().unwrap_or_default();
+        if accepted {
+            log::info!("RCPT TO:<{}> accepted: {}", address, reply);
+        } else {
+            log::warn!("RCPT TO:<{}> rejected: {}", address, reply);
+        }
+        self.smtp_connection
+            .recipient_results
+            .push(stream::RecipientResult {
+                address,
+                accepted,
+                reply,
+            });
+    }
+
     // Helper funtion to write to stream and return next state ok or error
     async fn write_and_get_next_state(
         &mut self,
@@ -212,13 +446,32 @@ impl StateMachine {
         msg_ok: &str,
         state_error: State,
     ) -> State {
-        log::info!("Sending ... {}\\r\\n", data);
-        match self
-            .smtp_connection
-            // send base64 encoded password
-            .write(format!("{}\r\n", data).as_bytes()) // base64 encoded "password"
-            .await
-        {
+        let command = match Command::from_line(data) {
+            // lines without a verb answer an AUTH challenge
+            Command::Other
+                if matches!(
+                    self.smtp_connection.last_command,
+                    Command::Auth | Command::AuthResponse
+                ) =>
+            {
+                Command::AuthResponse
+            }
+            command => command,
+        };
+        // AUTH lines carry the credentials, only the mechanism is logged
+        match command {
+            Command::Auth => match data.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                [verb, mechanism, _] => {
+                    log::info!("Sending ... {verb} {mechanism} {}\\r\\n", secret::REDACTED)
+                }
+                _ => log::info!("Sending ... {}\\r\\n", data),
+            },
+            Command::AuthResponse => log::info!("Sending ... {}\\r\\n", secret::REDACTED),
+            _ => log::info!("Sending ... {}\\r\\n", data),
+        }
+        self.smtp_connection.last_command = command;
+        let line = Zeroizing::new(format!("{}\r\n", data));
+        match self.smtp_connection.write(line.as_bytes()).await {
             Ok(_) => {
                 log::info!("{}", msg_ok);
                 state_ok
@@ -230,65 +483,445 @@ impl StateMachine {
         }
     }
 
-    pub fn new_from_env() -> Self {
-        dotenv().ok();
-        let smtp_server_and_port = env::var("smtp_server").expect("smtp_server .env not set");
+    /// Connect and send the mail, until the state is Finished or Failed
+    pub async fn run(&mut self) {
+        let mut event_counter = 0;
+        let mut current_state = self.state.clone();
+        while match (&self.state, event_counter) {
+            (State::Start, _) => {
+                log::info!("Connecting to SMTP server...");
+                self.handle_event(Event::Connect).await;
+                true
+            }
+            (State::Finished, _) => false,
+            (_, i) if i > self.max_events() => {
+                log::error!(
+                    "Event counter exceeded {} iterations, exiting.",
+                    self.max_events()
+                );
+                false
+            }
+            (State::Failed, _) => {
+                log::error!("State machine failed, exiting.");
+                false
+            }
+            (_s, _i) => true,
+        } {
+            event_counter += 1;
+            log::debug!("Loop iteration: {} state:{:?}", event_counter, self.state);
+            // Check if the current stream is None
+            let new_event = state_events::get_event(&mut self.smtp_connection).await;
+            log::info!("Current event: {:?}", new_event);
+            self.handle_event(new_event).await;
+            if current_state != self.state {
+                log::info!("State changed from {:?} to {:?}", current_state, self.state);
+                current_state = self.state.clone();
+            }
+        }
+        for result in &self.smtp_connection.recipient_results {
+            log::info!(
+                "Recipient {} {}: {}",
+                result.address,
+                if result.accepted {
+                    "accepted"
+                } else {
+                    "rejected"
+                },
+                result.reply
+            );
+        }
+        if let Some(message_id) = &self.smtp_connection.message_id {
+            if self.state == State::Finished {
+                log::info!("Message-ID: {}", message_id);
+            }
+        }
+        // done, only after QUIT is a final reply expected, on failure the server waits for a command
+        if self.state == State::Finished {
+            let final_event = state_events::get_event(&mut self.smtp_connection).await;
+            log::info!("final event: {:?}", final_event);
+        }
+        log::info!("SMTP Done server");
+    }
+
+    /// Upper bound of replies expected for this mail: the fixed command sequence,
+    /// one RCPT TO per recipient and, once the size is known, one per BDAT chunk
+    pub fn max_events(&self) -> i32 {
+        let connection = &self.smtp_connection;
+        let chunks = match connection.chunking {
+            true => {
+                connection.message_size.unwrap_or_default() / connection.chunk_size.max(1) as u64
+                    + 1
+            }
+            false => 0,
+        };
+        15 + connection.envelope_recipients().len() as i32 + chunks as i32
+    }
+
+    /// The 4xx or 5xx reply the mail failed with, None when it failed without
+    /// one, e.g. the server could not be reached
+    pub fn error_reply(&self) -> Option<&Reply> {
+        self.error_reply.as_ref()
+    }
+
+    /// Build the message without connecting, the envelope is logged
+    pub fn dry_run(&mut self) -> io::Result<Vec<u8>> {
+        let message = send_body::render_message(&mut self.smtp_connection)?;
+        log::info!("MAIL FROM:<{}>", self.smtp_connection.from.address());
+        for recipient in self.smtp_connection.envelope_recipients() {
+            log::info!("RCPT TO:<{}>", recipient);
+        }
+        Ok(message)
+    }
+
+    /// Settings from the environment and the .env file
+    pub fn new_from_env() -> io::Result<Self> {
+        StateMachine::new_from_config(&Config::from_env())
+    }
+
+    /// Errors name the setting, or the profile key it came from
+    pub fn new_from_config(config: &Config) -> io::Result<Self> {
+        // server:port, or the port from smtp_port, or the default for smtp_security
+        let smtp_server_and_port = config.required("smtp_server")?;
         let parts: Vec<&str> = smtp_server_and_port.split(':').collect();
-        let (smtp_server, port) = match &parts[..] {
-            [server, port] => (
-                server.to_string(),
-                port.parse::<u16>().expect("Invalid port number"),
+        let parse_port = |key: &str, port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| config.error(key, format!("invalid port {port:?}")))
+        };
+        let (smtp_server, port) = match (&parts[..], config.var("smtp_port")) {
+            ([server, _], Some(port)) | ([server], Some(port)) => {
+                (server.to_string(), parse_port("smtp_port", &port)?)
+            }
+            ([server, port], None) => (server.to_string(), parse_port("smtp_server", port)?),
+            ([server], None) => {
+                let implicit = config.var("smtp_security").and_then(|s| {
+                    stream::ConnectionSecurity::from_name(&s)
+                        .filter(|s| *s == stream::ConnectionSecurity::ImplicitTls)
+                });
+                (
+                    server.to_string(),
+                    if implicit.is_some() { 465 } else { 587 },
+                )
+            }
+            _ => {
+                return Err(config.error(
+                    "smtp_server",
+                    format!("invalid value {smtp_server_and_port:?}, expected host or host:port"),
+                ))
+            }
+        };
+        // without a username AUTH is skipped, e.g. for an internal relay
+        let smtp_username = config.var("smtp_username");
+        // plain, starttls or tls (implicit TLS, SMTPS), default from the port
+        let security = config
+            .parse(
+                "smtp_security",
+                stream::ConnectionSecurity::from_name,
+                "starttls, tls or plain",
+            )?
+            .unwrap_or_else(|| stream::ConnectionSecurity::default_for_port(port));
+        // required, opportunistic or disabled, default required unless smtp_security=plain
+        let tls_policy = config
+            .parse(
+                "smtp_tls_policy",
+                stream::TlsPolicy::from_name,
+                "required, opportunistic or disabled",
+            )?
+            .unwrap_or_else(|| stream::TlsPolicy::default_for(security));
+        if security == stream::ConnectionSecurity::Plain
+            && tls_policy == stream::TlsPolicy::Required
+        {
+            return Err(config.error(
+                "smtp_tls_policy",
+                "required conflicts with plain connection security, use opportunistic or disabled",
+            ));
+        }
+        let allow_plaintext_auth = config.bool("smtp_allow_plaintext_auth", false)?;
+        // BDAT when the server offers CHUNKING, chunk size in bytes
+        let chunking = config.bool("smtp_chunking", true)?;
+        // 8bit text parts when the server offers 8BITMIME, otherwise quoted-printable or base64
+        let eight_bit_mime = config.bool("smtp_8bitmime", true)?;
+        let chunk_size = config
+            .parse(
+                "smtp_bdat_chunk_size",
+                |value| value.trim().parse::<usize>().ok().filter(|size| *size > 0),
+                "a size in bytes",
+            )?
+            .unwrap_or(data::DEFAULT_CHUNK_SIZE);
+        // enforce or warn when the message exceeds the server SIZE limit
+        let size_policy = config
+            .parse(
+                "smtp_size_policy",
+                stream::SizePolicy::from_name,
+                "enforce or warn",
+            )?
+            .unwrap_or(stream::SizePolicy::Enforce);
+        let tls_config = tls::TlsConfig {
+            ca_files: config
+                .list("smtp_tls_ca_file")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            system_roots: config.bool("smtp_tls_system_roots", false)?,
+            client_cert: config.var("smtp_tls_client_cert").map(PathBuf::from),
+            client_key: config.var("smtp_tls_client_key").map(PathBuf::from),
+            pins_sha256: config
+                .list("smtp_tls_pin_sha256")
+                .iter()
+                .map(|pin| {
+                    tls::parse_pin(pin)
+                        .map_err(|e| config.error("smtp_tls_pin_sha256", format!("{pin}: {e}")))
+                })
+                .collect::<io::Result<_>>()?,
+            danger_accept_invalid_certs: config
+                .bool("smtp_tls_danger_accept_invalid_certs", false)?,
+        };
+        let token_provider = oauth_token_provider(config)?;
+        // with an OAuth token the password is not needed
+        let smtp_password = match (
+            Secret::from_config(config, "smtp_password")?,
+            &token_provider,
+        ) {
+            (Some(password), _) => Some(password),
+            (None, Some(_)) => None,
+            (None, None) if smtp_username.is_none() => None,
+            (None, None) => return Err(config.error(
+                "smtp_password",
+                "not set, or smtp_password_file, smtp_password_command or smtp_password_keyring",
+            )),
+        };
+        // optional, force an AUTH mechanism instead of picking from the server list
+        let auth_mechanism = config.parse(
+            "smtp_auth_mechanism",
+            auth::Mechanism::from_name,
+            "PLAIN, LOGIN, CRAM-MD5, XOAUTH2 or OAUTHBEARER",
+        )?;
+        // a complete message sent as is, "-" reads it from stdin
+        let stop_at_dot = config.bool("smtp_eml_stop_at_dot", false)?;
+        let mut raw_message = match config.var("smtp_eml_path") {
+            Some(path) => Some(
+                read_input(config, &path)
+                    .map(|data| {
+                        if stop_at_dot {
+                            until_dot_line(data)
+                        } else {
+                            data
+                        }
+                    })
+                    .and_then(mime::Message::from_raw)
+                    .map_err(|e| config.error("smtp_eml_path", format!("{path}: {e}")))?,
             ),
-            _ => panic!("Invalid format for smtp_server, expected 'server:port'"),
+            None => None,
+        };
+        // addresses may carry a display name: "Ops Bot" <ops@example.com>
+        // the sender of a raw message defaults to its From header
+        let from = match config.var("smtp_from") {
+            Some(from) => {
+                address::Mailbox::parse(&from).map_err(|e| config.error("smtp_from", e))?
+            }
+            None => {
+                let header = raw_message.as_ref().and_then(|m| m.header("From"));
+                let header = header.ok_or_else(|| config.error("smtp_from", "not set"))?;
+                address::Mailbox::parse(header).map_err(|e| {
+                    config.error("smtp_eml_path", format!("invalid From header: {e}"))
+                })?
+            }
         };
-        let smtp_username = env::var("smtp_username").expect("smtp_username .env not set");
-        let smtp_password = env::var("smtp_password").expect("smtp_password .env not set");
-        let from = env::var("smtp_from").expect("smtp_from .env not set");
-        let to = env::var("smtp_to").expect("smtp_to .env not set");
-        // debug
-        let debug = env::var("smtp_debug").unwrap_or_else(|_| "false".to_string());
-        let debug = match debug.as_str() {
-            "true" => true,
-            "True" => true,
-            "TRUE" => true,
-            "1" => true,
-            "false" => false,
-            "False" => false,
-            "FALSE" => false,
-            "0" => false,
-            _ => panic!("Invalid value for .env smtp_debug: {}", debug),
+        let from = match config.var("smtp_from_name") {
+            Some(name) => address::Mailbox::new(Some(&name), from.address())
+                .map_err(|e| config.error("smtp_from_name", e))?,
+            None => from,
         };
+        // sendmail adds the headers scripts often leave out
+        if config.bool("smtp_eml_complete_headers", false)? {
+            if let Some(message) = raw_message.as_mut() {
+                message.complete_headers(&from);
+            }
+        }
+        let mut to = config.mailboxes("smtp_to")?;
+        let mut cc = config.mailboxes("smtp_cc")?;
+        let mut bcc = config.mailboxes("smtp_bcc")?;
+        // sendmail -t: recipients from the message headers, Bcc is removed before sending
+        if config.bool("smtp_extract_recipients", false)? {
+            let message = raw_message
+                .as_mut()
+                .ok_or_else(|| config.error("smtp_extract_recipients", "needs smtp_eml_path"))?;
+            for (name, list) in [("To", &mut to), ("Cc", &mut cc), ("Bcc", &mut bcc)] {
+                for value in message.headers(name) {
+                    list.extend(address::parse_list(value).map_err(|e| {
+                        config.error("smtp_eml_path", format!("invalid {name} header: {e}"))
+                    })?);
+                }
+            }
+            message.remove_header("Bcc");
+        }
+        if to.is_empty() && cc.is_empty() && bcc.is_empty() {
+            return Err(config.error("smtp_to", "not set"));
+        }
+        let reply_to = config.mailboxes("smtp_reply_to")?;
+        // optional extra headers, checked for line breaks when the message is built
+        let mut headers: Vec<(String, String)> = [
+            ("smtp_in_reply_to", "In-Reply-To"),
+            ("smtp_references", "References"),
+            ("smtp_list_unsubscribe", "List-Unsubscribe"),
+        ]
+        .iter()
+        .filter_map(|(var, name)| config.var(var).map(|v| (name.to_string(), v)))
+        .collect();
+        let priority = config.parse(
+            "smtp_priority",
+            |priority| match priority.trim().to_ascii_lowercase().as_str() {
+                "highest" => Some(1),
+                "high" => Some(2),
+                "normal" => Some(3),
+                "low" => Some(4),
+                "lowest" => Some(5),
+                n => n.parse().ok().filter(|n| (1..=5).contains(n)),
+            },
+            "highest, high, normal, low, lowest or 1-5",
+        )?;
+        if let Some(level) = priority {
+            let value = mime::header::priority(level).to_string();
+            headers.push(("X-Priority".to_string(), value));
+        }
         // subject has default fallback
-        let subject = env::var("smtp_subject").unwrap_or_else(|_| {
+        let subject = config.var("smtp_subject").unwrap_or_else(|| {
             format!(
                 "Test mail Rust OpenSSL - smtp email sent with attachement at {}",
                 chrono::Local::now()
             )
         });
 
-        // Read the attachment file (e.g., a small text file or PDF)
-        let smtp_attachment_path = env::var("smtp_attachment_path").ok(); //Result into Option
-        let attachment_data = if let Some(ref path_provided) = smtp_attachment_path {
-            Some(fs::read(&path_provided)
-                .expect(&format!("Failed to read attachment file {path_provided}")))
-        } else {
-            None
+        // Mail body, the demo text is sent when neither is set
+        let read_body = |name: &str| match config.var(name) {
+            Some(path) => read_input(config, &path)
+                .and_then(|data| String::from_utf8(data).map_err(io::Error::other))
+                .map(Some)
+                .map_err(|e| config.error(name, format!("{path}: {e}"))),
+            None => Ok(None),
+        };
+        let text_body = match config.var("smtp_text") {
+            Some(text) => Some(text),
+            None => read_body("smtp_text_path")?,
         };
+        let html_body = read_body("smtp_html_path")?;
+        // Images referenced from the HTML body as cid:<file name>
+        let inline_images: Vec<mime::Attachment> = config
+            .list("smtp_inline_path")
+            .iter()
+            .map(|path| {
+                let image = mime::Attachment::from_path(path)
+                    .map_err(|e| config.error("smtp_inline_path", e))?;
+                let cid = image.filename.clone();
+                Ok(image.inline(&cid))
+            })
+            .collect::<io::Result<_>>()?;
+        // Read the attachment files (e.g., a small text file or PDF), type guessed from the content
+        let attachments: Vec<mime::Attachment> = config
+            .list("smtp_attachment_path")
+            .iter()
+            .map(|path| {
+                mime::Attachment::from_path(path)
+                    .map_err(|e| config.error("smtp_attachment_path", e))
+            })
+            .collect::<io::Result<_>>()?;
 
-        StateMachine {
+        let mut smtp_connection = stream::SmtpConnection::new(
+            &smtp_server,
+            port,
+            smtp_username.as_deref(),
+            smtp_password,
+            from,
+            to,
+            &subject,
+        );
+        smtp_connection.security = security;
+        smtp_connection.tls_policy = tls_policy;
+        smtp_connection.allow_plaintext_auth = allow_plaintext_auth;
+        smtp_connection.size_policy = size_policy;
+        smtp_connection.chunking = chunking;
+        smtp_connection.eight_bit_mime = eight_bit_mime;
+        smtp_connection.chunk_size = chunk_size;
+        smtp_connection.tls_config = tls_config;
+        smtp_connection.auth_mechanism = auth_mechanism;
+        smtp_connection.token_provider = token_provider;
+        smtp_connection.cc = cc;
+        smtp_connection.bcc = bcc;
+        smtp_connection.reply_to = reply_to;
+        smtp_connection.headers = headers;
+        smtp_connection.text_body = text_body;
+        smtp_connection.html_body = html_body;
+        smtp_connection.inline_images = inline_images;
+        smtp_connection.attachments = attachments;
+        smtp_connection.progress = Some(data::log_progress());
+        smtp_connection.message = raw_message;
+        Ok(StateMachine {
             state: State::Start,
-            smtp_connection: stream::SmtpConnection {
-                smtp_stream: stream::Stream::None,
-                host: smtp_server,
-                port,
-                username: Some(smtp_username),
-                password: Some(smtp_password),
-                attachement_name: smtp_attachment_path,
-                attachement_data: attachment_data,
-                from: from,
-                to: to,
-                subject: subject,
-            },
+            smtp_connection,
+            authenticator: None,
+            error_reply: None,
+        })
+    }
+}
+
+// Contents of a file, or of stdin for "-" unless the config holds the input
+fn read_input(config: &Config, path: &str) -> io::Result<Vec<u8>> {
+    match (path, config.input()) {
+        ("-", Some(input)) => Ok(input.to_vec()),
+        ("-", None) => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Ok(data)
         }
+        (path, _) => std::fs::read(path),
+    }
+}
+
+// sendmail without -i: a line with a single "." ends the input
+fn until_dot_line(mut data: Vec<u8>) -> Vec<u8> {
+    let mut start = 0;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if matches!(line, b".\n" | b".\r\n" | b".") {
+            break;
+        }
+        start += line.len();
+    }
+    data.truncate(start);
+    data
+}
+
+// smtp_oauth_token for a ready access token, or smtp_oauth_refresh_token with
+// smtp_oauth_token_url and smtp_oauth_client_id to fetch one at AUTH time
+// each may also come from a _file, _command or _keyring variant
+fn oauth_token_provider(config: &Config) -> io::Result<Option<Box<dyn oauth::TokenProvider>>> {
+    if let Some(token) = Secret::from_config(config, "smtp_oauth_token")? {
+        return Ok(Some(Box::new(oauth::StaticToken(token))));
+    }
+    let Some(refresh_token) = Secret::from_config(config, "smtp_oauth_refresh_token")? else {
+        return Ok(None);
+    };
+    let token_url = config.required("smtp_oauth_token_url")?;
+    let client_id = config.required("smtp_oauth_client_id")?;
+    let mut provider = oauth::RefreshTokenProvider::new(&token_url, &client_id, refresh_token);
+    provider.client_secret = Secret::from_config(config, "smtp_oauth_client_secret")?;
+    provider.scope = config.var("smtp_oauth_scope");
+    Ok(Some(Box::new(provider)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_ends_at_dot_line() {
+        let data = b"Subject: hi\n\nbody\n.\nafter\n".to_vec();
+        assert_eq!(until_dot_line(data), b"Subject: hi\n\nbody\n");
+        let data = b"Subject: hi\r\n\r\n..stuffed\r\n.\r\n".to_vec();
+        assert_eq!(until_dot_line(data), b"Subject: hi\r\n\r\n..stuffed\r\n");
+        // a dot on the last line without a line ending also ends the input
+        assert_eq!(until_dot_line(b"body\n.".to_vec()), b"body\n");
+        // only a line with nothing but the dot counts
+        let data = b"body\n. \n.x\nend".to_vec();
+        assert_eq!(until_dot_line(data.clone()), data);
     }
 }
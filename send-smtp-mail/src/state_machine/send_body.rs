@@ -1,139 +1,231 @@
+use crate::data::{self, ChunkReader, DataEncoder, MessageReader, Progress};
+use crate::mime::{Message, MAX_LINE_LENGTH};
+use crate::state_events::Command;
 use crate::state_machine::State;
 use crate::stream::SmtpConnection; // Import State from the appropriate module
+use std::io;
 
-use base64::engine::general_purpose::STANDARD as b64;
-use base64::Engine; // trait
-
-fn chunk_and_encode(data: &Vec<u8>, chunk_size: usize) -> Vec<Vec<u8>> {
-    // Base64 encode the entire data
-    let encoded = b64.encode(data);
-
-    // Handle chunk_size == 0: return all data in one entry with \r\n
-    if chunk_size == 0 {
-        let chunk = encoded.into_bytes();
-        // chunk.extend_from_slice(b"\r\n");
-        return vec![chunk];
+// The message used when the caller did not set one, built from the connection fields
+fn default_message(smtp: &SmtpConnection) -> io::Result<Message> {
+    // UTF-8 addresses can not be downgraded, headers are only sent as UTF-8 when
+    // an address needs SMTPUTF8 anyway, otherwise encoded words work everywhere
+    let utf8_address = std::iter::once(&smtp.from)
+        .chain(&smtp.to)
+        .chain(&smtp.cc)
+        .chain(&smtp.bcc)
+        .chain(&smtp.reply_to)
+        .find(|m| m.is_utf8());
+    if let Some(mailbox) = utf8_address {
+        if !smtp.capabilities.smtputf8 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Address {} needs SMTPUTF8 which {} does not support",
+                    mailbox.address(),
+                    smtp.host
+                ),
+            ));
+        }
     }
-
-    // Reduce chunk_size to the nearest lower multiple of 4
-    let adjusted_chunk_size = (chunk_size / 4) * 4;
-
-    // If adjusted_chunk_size is 0, use 4 as the minimum
-    let final_chunk_size = if adjusted_chunk_size == 0 {
-        4
-    } else {
-        adjusted_chunk_size
-    };
-
-    // Chunk the base64-encoded bytes and append \r\n to each
-    encoded
-        .as_bytes()
-        .chunks(final_chunk_size)
-        .map(|chunk| {
-            let mut chunk_vec = chunk.to_vec();
-            chunk_vec.extend_from_slice(b"\r\n");
-            chunk_vec
-        })
-        .collect()
+    // Bcc recipients are only given in the envelope, never in the headers
+    let mut builder = Message::builder()
+        .utf8(utf8_address.is_some())
+        .eight_bit(smtp.eight_bit_mime && smtp.capabilities.eight_bit_mime)
+        .from(&smtp.from)
+        .to(&smtp.to)
+        .cc(&smtp.cc)
+        .reply_to(&smtp.reply_to)
+        .subject(&smtp.subject);
+    for (name, value) in &smtp.headers {
+        builder = builder.header(name, value);
+    }
+    match (&smtp.text_body, &smtp.html_body) {
+        (None, None) => builder = builder.text(&demo_text(smtp)),
+        (text, html) => {
+            if let Some(text) = text {
+                builder = builder.text(text);
+            }
+            if let Some(html) = html {
+                builder = builder.html(html);
+            }
+        }
+    }
+    for image in &smtp.inline_images {
+        builder = builder.inline(image.clone());
+    }
+    for attachment in &smtp.attachments {
+        builder = builder.attachment(attachment.clone());
+    }
+    builder.build()
 }
 
-pub async fn send_body(smtp: &mut SmtpConnection) -> State {
-    // log4::init_log();
-    // Send the email body
-    log::info!("Sending email body...");
-    // Boundary up to 70 chars, all starts with -- and only last boundary ends with --
-    let boundary = "boundary123456789";
-    let attachment_data_b64 = chunk_and_encode(
-        &smtp.attachement_data.as_ref().unwrap_or(&Vec::new()),
-        0, // 1MB chunks
-    );
-    // Send the email headers and body
-    let data_header = format!(
-        "From: {from}\r\n\
-        To: {to}\r\n\
-        Subject: {subject}\r\n\
-        MIME-Version: 1.0\r\n\
-        Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
-        \r\n\
-        --{boundary}\r\n\
-        Content-Type: text/plain; charset=utf-8\r\n\r\n",
-        from = smtp.from.clone(),
-        to = smtp.to.clone(),
-        subject = smtp.subject.clone(),
-        boundary = boundary,
-    );
-    let data_msg = format!(
+fn demo_text(smtp: &SmtpConnection) -> String {
+    let filenames = smtp
+        .attachments
+        .iter()
+        .map(|a| format!("'{}'", a.filename))
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!(
         "This is the email body.\r\n\
         \r\n\
         Was sent from {from} to {to}.\r\n\
         \r\n\
         Subject: \"{subject}\"\r\n\
         \r\n\
-        See the attached file! '{filename}'\r\n\
-        \r\n",
-        from = smtp.from.clone(),
-        to = smtp.to.clone(),
-        subject = smtp.subject.clone(),
-        filename = smtp
-            .attachement_name
-            .clone()
-            .unwrap_or_default()
-            .to_string(),
-    );
-    smtp.write(format!("{}{}\r\n", data_header, data_msg).as_bytes())
-        .await
-        .unwrap();
-    log::info!("Email headers and body sent.");
+        See the attached files! {filenames}\r\n",
+        from = smtp.from,
+        to = smtp
+            .to
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+        subject = smtp.subject,
+    )
+}
 
-    if attachment_data_b64.is_empty() || smtp.attachement_name.is_none() {
-        log::info!("No attachment data to send.");
-        smtp.write(format!("\r\n--{boundary}--\r\n").as_bytes())
-        .await
-        .unwrap();
-        smtp.write(b"\r\n.\r\n").await.unwrap();
-        return State::MailSent;
+/// Build the default message unless the caller set one, done before MAIL FROM so
+/// invalid headers are reported before the mail transaction starts
+pub fn prepare_message(smtp: &mut SmtpConnection) -> io::Result<()> {
+    if smtp.message.is_none() {
+        smtp.message = Some(default_message(smtp)?);
     }
-    // Send the attachment
-    log::info!("Sending attachment... {}", smtp.attachement_name.clone().unwrap_or_default());
+    let message = smtp.message.as_ref();
+    smtp.message_id = message.and_then(Message::message_id).map(str::to_string);
+    smtp.message_size = message.map(|m| data::data_size(&m.segments()));
+    Ok(())
+}
+
+/// The message as it would be sent, for --dry-run
+pub fn render_message(smtp: &mut SmtpConnection) -> io::Result<Vec<u8>> {
+    prepare_message(smtp)?;
+    match smtp.message.as_ref() {
+        Some(message) => message.render(),
+        None => Err(io::Error::other("No message to send")),
+    }
+}
+
+pub async fn send_body(smtp: &mut SmtpConnection) -> State {
+    log::info!("Sending email body...");
     let start_send = std::time::Instant::now();
-    let mut send_size = 0;
-    smtp.write(
-        format!(
-            "--{boundary}\r\n\
-            Content-Type: application/octet-stream\r\n\
-            Content-Disposition: attachment; filename=\"{}\"\r\n\
-            Content-Transfer-Encoding: base64\r\n\r\n",
-            smtp.attachement_name.clone().unwrap_or_default()
-        )
-        .as_bytes(),
-    )
-    .await
-    .unwrap();
-    for (i, chunk) in attachment_data_b64.iter().enumerate() {
-        smtp.write(&chunk).await.unwrap();
-        send_size += chunk.len();
-        if send_size % (1024 * 1024) == 0 {
-            log::info!(
-                "Attachment chunk {} sent. size:{} bytes {:.2} Mb",
-                i,
-                send_size,
-                send_size as f64 / (1024.0 * 1024.0)
-            );
-        }
+    if let Err(e) = prepare_message(smtp) {
+        log::error!("Failed to build email: {}", e);
+        return State::Failed;
     }
-    smtp.flush().await.unwrap();
+    let sent = match write_message(smtp).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            log::error!("Failed to send email body: {}", e);
+            return State::Failed;
+        }
+    };
+    smtp.last_command = Command::DataEnd;
     log::info!(
-        "Attachment sent. size:{}b = {:.2}Mb in {:.2}sec",
-        send_size,
-        send_size as f64 / (1024.0 * 1024.0),
+        "Email sent. Message-ID:{} size:{}b = {:.2}Mb in {:.2}sec",
+        smtp.message_id.as_deref().unwrap_or("none"),
+        sent,
+        sent as f64 / (1024.0 * 1024.0),
         start_send.elapsed().as_secs_f64()
     );
-    smtp.write(format!("\r\n\r\n--{boundary}--\r\n\r\n").as_bytes())
-        .await
-        .unwrap();
-    smtp.flush().await.unwrap();
-    smtp.write("\r\n.\r\n".as_bytes()).await.unwrap();
-    smtp.flush().await.unwrap();
-    log::info!("Final boundary and dot . sent.");
+    State::MailSent
+}
+
+// Stream the message dot-stuffed, returns the number of bytes written
+async fn write_message(smtp: &mut SmtpConnection) -> io::Result<u64> {
+    let Some(message) = smtp.message.as_ref() else {
+        return Err(io::Error::other("No message to send"));
+    };
+    let segments = message.segments();
+    let mut progress = Progress {
+        sent: 0,
+        total: segments.iter().map(|s| s.encoded_len()).sum(),
+    };
+    let mut reader = MessageReader::new(segments);
+    let mut encoder = DataEncoder::new();
+    let mut written = 0;
+    while let Some(chunk) = reader.next_chunk().await? {
+        let data = encoder.encode(&chunk);
+        smtp.write(&data).await?;
+        written += data.len() as u64;
+        progress.sent += chunk.len() as u64;
+        if let Some(callback) = smtp.progress.as_mut() {
+            callback(&progress);
+        }
+    }
+    let end = encoder.finish();
+    smtp.write(&end).await?;
+    smtp.flush().await?;
+    if encoder.max_line_length() > MAX_LINE_LENGTH {
+        log::warn!(
+            "Message has a line of {} characters, servers may reject lines over {}",
+            encoder.max_line_length(),
+            MAX_LINE_LENGTH
+        );
+    }
+    Ok(written + end.len() as u64)
+}
+
+/// Send the next BDAT chunk, the first call starts the transfer. Each chunk is
+/// answered with its own 250, after the LAST chunk the reply is the delivery result.
+pub async fn send_chunk(smtp: &mut SmtpConnection) -> State {
+    if smtp.chunk_reader.is_none() {
+        if let Err(e) = prepare_message(smtp) {
+            log::error!("Failed to build email: {}", e);
+            return State::Failed;
+        }
+        let Some(message) = smtp.message.as_ref() else {
+            log::error!("No message to send");
+            return State::Failed;
+        };
+        log::info!(
+            "Sending email body in BDAT chunks of {} bytes...",
+            smtp.chunk_size
+        );
+        smtp.chunk_reader = Some(ChunkReader::new(message.segments(), smtp.chunk_size));
+    }
+    let Some(reader) = smtp.chunk_reader.as_mut() else {
+        return State::Failed;
+    };
+    let (chunk, last) = match reader.next_chunk().await {
+        Ok(next) => next,
+        Err(e) => {
+            // the server waits for the announced bytes, a partial chunk can not be sent
+            log::error!("Failed to read email body: {}", e);
+            return State::Failed;
+        }
+    };
+    let progress = reader.progress();
+    let started = reader.started();
+    let command = match last {
+        true => format!("BDAT {} LAST", chunk.len()),
+        false => format!("BDAT {}", chunk.len()),
+    };
+    log::info!("Sending ... {}\\r\\n", command);
+    let result = async {
+        smtp.write(format!("{}\r\n", command).as_bytes()).await?;
+        smtp.write(&chunk).await?;
+        smtp.flush().await
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to send BDAT chunk: {}", e);
+        return State::Failed;
+    }
+    smtp.last_command = Command::from_line(&command);
+    if let Some(callback) = smtp.progress.as_mut() {
+        callback(&progress);
+    }
+    if !last {
+        return State::SendingMailChunks;
+    }
+    smtp.chunk_reader = None;
+    log::info!(
+        "Email sent. Message-ID:{} size:{}b = {:.2}Mb in {:.2}sec",
+        smtp.message_id.as_deref().unwrap_or("none"),
+        progress.sent,
+        progress.sent as f64 / (1024.0 * 1024.0),
+        started.elapsed().as_secs_f64()
+    );
     State::MailSent
 }
@@ -1,3 +1,13 @@
+use crate::address::Mailbox;
+use crate::auth::Mechanism;
+use crate::capabilities::ServerCapabilities;
+use crate::data::{self, ChunkReader, ProgressCallback};
+use crate::mime::{Attachment, Message};
+use crate::oauth::TokenProvider;
+use crate::reply::{self, Reply};
+use crate::secret::Secret;
+use crate::state_events::Command;
+use crate::tls::{self, TlsConfig};
 use std::io;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
@@ -7,45 +17,175 @@ use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
+#[allow(clippy::enum_variant_names)] // variants named after the wrapped stream types
 pub enum Stream {
     TcpStream(TcpStream),
-    TlsStream(TlsStream<TcpStream>),
+    TlsStream(Box<TlsStream<TcpStream>>),
     None, // Placeholder for no stream as we swap streams
 }
 
+// RFC 5321 limits reply lines to 512 octets, allow some slack for chatty servers
+const MAX_REPLY_LINE_LENGTH: usize = 4096;
+// Guard against a server streaming endless continuation lines
+const MAX_REPLY_LINES: usize = 256;
+
+/// How the connection to the server is secured
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConnectionSecurity {
+    Plain,       // never use TLS
+    StartTls,    // plain TCP upgraded with STARTTLS, usually port 587 or 25
+    ImplicitTls, // TLS handshake straight after connect (SMTPS), usually port 465
+}
+impl ConnectionSecurity {
+    pub fn from_name(name: &str) -> Option<ConnectionSecurity> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "plain" | "none" => Some(ConnectionSecurity::Plain),
+            "starttls" => Some(ConnectionSecurity::StartTls),
+            "tls" | "implicit" | "implicittls" | "smtps" | "ssl" => {
+                Some(ConnectionSecurity::ImplicitTls)
+            }
+            _ => None,
+        }
+    }
+    pub fn default_for_port(port: u16) -> ConnectionSecurity {
+        match port {
+            465 => ConnectionSecurity::ImplicitTls,
+            _ => ConnectionSecurity::StartTls,
+        }
+    }
+}
+
+/// Whether TLS must be used before credentials and mail are sent
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TlsPolicy {
+    Required,      // fail if STARTTLS is not offered or the upgrade fails
+    Opportunistic, // use STARTTLS when offered, otherwise continue in plaintext
+    Disabled,      // never send STARTTLS
+}
+impl TlsPolicy {
+    pub fn from_name(name: &str) -> Option<TlsPolicy> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "required" | "require" => Some(TlsPolicy::Required),
+            "opportunistic" => Some(TlsPolicy::Opportunistic),
+            "disabled" | "none" => Some(TlsPolicy::Disabled),
+            _ => None,
+        }
+    }
+    pub fn default_for(security: ConnectionSecurity) -> TlsPolicy {
+        match security {
+            ConnectionSecurity::Plain => TlsPolicy::Disabled,
+            _ => TlsPolicy::Required,
+        }
+    }
+}
+
+/// What to do when the message is larger than the server SIZE limit
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SizePolicy {
+    Enforce, // fail before MAIL FROM, nothing is sent
+    Warn,    // log and send anyway, the server may still accept or reject it
+}
+impl SizePolicy {
+    pub fn from_name(name: &str) -> Option<SizePolicy> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "enforce" | "error" => Some(SizePolicy::Enforce),
+            "warn" | "ignore" => Some(SizePolicy::Warn),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of the RCPT TO command for one envelope recipient
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecipientResult {
+    pub address: String,
+    pub accepted: bool,
+    pub reply: Reply,
+}
+
 pub struct SmtpConnection {
     pub smtp_stream: Stream, // Tcp or Tls stream
     pub host: String,
     pub port: u16,
+    pub security: ConnectionSecurity,
+    pub tls_policy: TlsPolicy,
+    pub allow_plaintext_auth: bool, // send credentials without TLS, off by default
+    pub tls_config: TlsConfig,
     pub username: Option<String>,
-    pub password: Option<String>,
-    pub from: String,
-    pub to: String,
+    pub password: Option<Secret>,
+    pub auth_mechanism: Option<Mechanism>, // None = choose from the EHLO AUTH list
+    pub token_provider: Option<Box<dyn TokenProvider>>, // OAuth token for XOAUTH2 / OAUTHBEARER
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+    pub cc: Vec<Mailbox>,
+    pub bcc: Vec<Mailbox>, // envelope only, never rendered in the headers
+    pub recipient_results: Vec<RecipientResult>, // one per RCPT TO sent, in order
     pub subject: String,
-    pub attachement_name: Option<String>,
-    pub attachement_data: Option<Vec<u8>>,
+    pub last_command: Command, // used to interpret the next reply
+    pub capabilities: ServerCapabilities, // from the most recent EHLO reply
+    read_buffer: Vec<u8>,      // bytes received but not yet consumed as a reply
+    pub reply_to: Vec<Mailbox>,
+    pub headers: Vec<(String, String)>, // extra headers e.g. In-Reply-To, List-Unsubscribe
+    pub text_body: Option<String>,
+    pub html_body: Option<String>, // with inline_images referenced as cid:<file name>
+    pub inline_images: Vec<Attachment>,
+    pub attachments: Vec<Attachment>,
+    pub message: Option<Message>, // sent as is, otherwise built from the fields above
+    pub message_id: Option<String>, // of the message sent, for tracking
+    pub message_size: Option<u64>, // DATA size in bytes, declared with MAIL FROM SIZE=
+    pub size_policy: SizePolicy,
+    pub chunking: bool,       // use BDAT when the server advertises CHUNKING
+    pub eight_bit_mime: bool, // send text 8bit when the server advertises 8BITMIME
+    pub chunk_size: usize,
+    pub chunk_reader: Option<ChunkReader>, // BDAT transfer in progress
+    pub progress: Option<ProgressCallback>, // called while the message is written
 }
 impl SmtpConnection {
     pub fn new(
         host: &str,
         port: u16,
         username: Option<&str>,
-        password: Option<&str>,
-        from: &str,
-        to: &str,
+        password: Option<Secret>,
+        from: Mailbox,
+        to: Vec<Mailbox>,
         subject: &str,
     ) -> Self {
         SmtpConnection {
             smtp_stream: Stream::None,
             host: host.to_string(),
             port,
+            security: ConnectionSecurity::default_for_port(port),
+            tls_policy: TlsPolicy::Required,
+            allow_plaintext_auth: false,
+            tls_config: TlsConfig::default(),
             username: username.map(|s| s.to_string()),
-            password: password.map(|s| s.to_string()),
-            from: from.to_string(),
-            to: to.to_string(),
+            password,
+            auth_mechanism: None,
+            token_provider: None,
+            from,
+            to,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            recipient_results: Vec::new(),
             subject: subject.to_string(),
-            attachement_name: None,
-            attachement_data: None,
+            last_command: Command::None,
+            capabilities: ServerCapabilities::default(),
+            read_buffer: Vec::new(),
+            reply_to: Vec::new(),
+            headers: Vec::new(),
+            text_body: None,
+            html_body: None,
+            inline_images: Vec::new(),
+            attachments: Vec::new(),
+            message: None,
+            message_id: None,
+            message_size: None,
+            size_policy: SizePolicy::Enforce,
+            chunking: true,
+            eight_bit_mime: true,
+            chunk_size: data::DEFAULT_CHUNK_SIZE,
+            chunk_reader: None,
+            progress: None,
         }
     }
     pub async fn connect_to_server(&mut self) -> Result<(), io::Error> {
@@ -59,65 +199,214 @@ impl SmtpConnection {
         //let smtp_stream = stream::Stream::new(stream, host, port);
         log::debug!("Connected to SMTP server at {}", addr);
         self.smtp_stream = Stream::TcpStream(tcp_stream);
+        if self.security == ConnectionSecurity::ImplicitTls {
+            self.switch_to_tls().await?;
+        }
         Ok(())
     }
 
     /// Upgrade the existing TCP stream to a TLS stream
     pub async fn switch_to_tls(&mut self) -> io::Result<()> {
-        // Configure rustls, root certificates used by Mozilla
-        let root_store = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-        };
-
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+        // Configure rustls, Mozilla roots plus whatever tls_config adds
+        let config = self.tls_config.client_config()?;
         let connector = TlsConnector::from(Arc::new(config));
 
         // Perform TLS handshake
         let domain = rustls::pki_types::ServerName::try_from(self.host.clone())
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid hostname"))?
             .to_owned();
+        // Anything already buffered was sent in plaintext before the handshake,
+        // accepting it would allow STARTTLS command injection (CVE-2011-0411)
+        if !self.read_buffer.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Server sent data after STARTTLS reply before TLS handshake",
+            ));
+        }
         // extract current TCP stream, get value by swapping with None
         // This is a workaround to avoid borrowing issues with the TcpStream
         let tls_stream = match std::mem::replace(&mut self.smtp_stream, Stream::None) {
-            Stream::TcpStream(tcp) => connector.connect(domain, tcp).await?,
+            Stream::TcpStream(tcp) => Box::new(connector.connect(domain, tcp).await?),
             Stream::TlsStream(tls) => tls,
-            Stream::None => return Err(io::Error::new(io::ErrorKind::Other, "Stream is None")),
+            Stream::None => return Err(io::Error::other("Stream is None")),
         };
+        tls::log_session(tls_stream.get_ref().1, &self.host);
         self.smtp_stream = Stream::TlsStream(tls_stream);
+        // RFC 3207: forget everything learned before TLS, EHLO must be sent again
+        self.capabilities = ServerCapabilities::default();
         log::info!("TLS handshake completed");
         Ok(())
     }
 
+    /// All envelope recipient addresses To, Cc then Bcc, duplicates removed
+    pub fn envelope_recipients(&self) -> Vec<String> {
+        let mut recipients: Vec<String> = Vec::new();
+        for mailbox in self.to.iter().chain(&self.cc).chain(&self.bcc) {
+            let address = mailbox.address();
+            if !recipients.iter().any(|r| r.eq_ignore_ascii_case(address)) {
+                recipients.push(address.to_string());
+            }
+        }
+        recipients
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.smtp_stream, Stream::TlsStream(_))
+    }
+
     pub async fn flush(&mut self) -> io::Result<()> {
         match &mut self.smtp_stream {
             Stream::TcpStream(s) => s.flush().await?,
             Stream::TlsStream(s) => s.flush().await?,
-            Stream::None => return Err(io::Error::new(io::ErrorKind::Other, "Stream is None")),
+            Stream::None => return Err(io::Error::other("Stream is None")),
         };
         Ok(())
     }
 
+    /// Write all of data, waits while the socket buffer is full
     pub async fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         match &mut self.smtp_stream {
-            Stream::TcpStream(s) => s.write(data).await?,
-            Stream::TlsStream(s) => s.write(data).await?,
-            Stream::None => return Err(io::Error::new(io::ErrorKind::Other, "Stream is None")),
+            Stream::TcpStream(s) => s.write_all(data).await?,
+            Stream::TlsStream(s) => s.write_all(data).await?,
+            Stream::None => return Err(io::Error::other("Stream is None")),
         };
         Ok(data.len())
     }
-    pub async fn read(&mut self) -> io::Result<String> {
-        // Buffer for reading server responses
+    /// Read exactly one complete (possibly multi-line) reply.
+    /// Bytes following the reply, e.g. a pipelined second reply, stay buffered for the next call.
+    pub async fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut raw_reply = String::new();
+        let mut line_count = 0;
+        loop {
+            // Consume complete lines already in the buffer
+            while let Some(pos) = self.read_buffer.iter().position(|&b| b == b'\n') {
+                if pos > MAX_REPLY_LINE_LENGTH {
+                    return Err(line_too_long());
+                }
+                let line_bytes: Vec<u8> = self.read_buffer.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line_bytes);
+                let line = line.trim_end_matches(['\r', '\n']);
+                log::debug!("Received line: {}", line);
+                let (_code, is_last, _text) = reply::parse_line(line)?;
+                raw_reply.push_str(line);
+                raw_reply.push_str("\r\n");
+                line_count += 1;
+                if is_last {
+                    return Reply::parse(&raw_reply);
+                }
+                if line_count >= MAX_REPLY_LINES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("SMTP reply exceeds {MAX_REPLY_LINES} lines"),
+                    ));
+                }
+            }
+            if self.read_buffer.len() > MAX_REPLY_LINE_LENGTH {
+                return Err(line_too_long());
+            }
+            if self.read().await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed by server",
+                ));
+            }
+        }
+    }
+
+    // Read whatever the stream has available and append it to the buffer
+    async fn read(&mut self) -> io::Result<usize> {
         let mut buf: [u8; 1024] = [0; 1024];
-        let bytes_read;
-        // let s = std::mem::replace(&mut self.smtp_stream, Stream::None);
-        // match s {
-        match &mut self.smtp_stream {
-            Stream::TcpStream(s) => bytes_read = s.read(&mut buf).await?,
-            Stream::TlsStream(s) => bytes_read = s.read(&mut buf).await?,
-            Stream::None => return Err(io::Error::new(io::ErrorKind::Other, "Stream is None")),
+        let bytes_read = match &mut self.smtp_stream {
+            Stream::TcpStream(s) => s.read(&mut buf).await?,
+            Stream::TlsStream(s) => s.read(&mut buf).await?,
+            Stream::None => return Err(io::Error::other("Stream is None")),
         };
-        Ok(String::from_utf8_lossy(&buf[..bytes_read]).to_string())
+        self.read_buffer.extend_from_slice(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("SMTP reply line exceeds {MAX_REPLY_LINE_LENGTH} bytes"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    // a connection to a local server that writes `writes` with a pause in between,
+    // so each arrives as its own segment
+    async fn connection(writes: Vec<Vec<u8>>) -> SmtpConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for data in writes {
+                stream.write_all(&data).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let from = Mailbox::parse("a@example.com").unwrap();
+        let mut connection =
+            SmtpConnection::new("127.0.0.1", addr.port(), None, None, from, vec![], "");
+        connection.smtp_stream = Stream::TcpStream(TcpStream::connect(addr).await.unwrap());
+        connection
+    }
+
+    #[tokio::test]
+    async fn reply_split_across_writes() {
+        let writes = vec![
+            b"250-smtp.exa".to_vec(),
+            b"mple.com\r\n250-SIZE 1000\r".to_vec(),
+            b"\n250 8BITMIME\r\n".to_vec(),
+        ];
+        let mut connection = connection(writes).await;
+        let reply = connection.read_reply().await.unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(reply.lines, ["smtp.example.com", "SIZE 1000", "8BITMIME"]);
+    }
+
+    #[tokio::test]
+    async fn pipelined_replies_stay_buffered() {
+        let writes = vec![b"250 2.1.0 Sender OK\r\n250 2.1.5 Recipient OK\r\n".to_vec()];
+        let mut connection = connection(writes).await;
+        let first = connection.read_reply().await.unwrap();
+        assert_eq!(first.lines, ["2.1.0 Sender OK"]);
+        assert!(connection.read_buffer.starts_with(b"250 2.1.5"));
+        let second = connection.read_reply().await.unwrap();
+        assert_eq!(second.lines, ["2.1.5 Recipient OK"]);
+        assert!(connection.read_buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reply_line_too_long() {
+        // complete in the buffer as well as still without its line end
+        for tail in [&b"\r\n"[..], b""] {
+            let mut line = b"250 ".to_vec();
+            line.resize(MAX_REPLY_LINE_LENGTH + 100, b'x');
+            line.extend_from_slice(tail);
+            let mut connection = connection(vec![line]).await;
+            let e = connection.read_reply().await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains("line exceeds"), "{e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_lines() {
+        let writes = vec![b"250-more\r\n".repeat(MAX_REPLY_LINES + 1)];
+        let mut connection = connection(writes).await;
+        let e = connection.read_reply().await.unwrap_err();
+        assert!(
+            e.to_string()
+                .contains(&format!("exceeds {MAX_REPLY_LINES} lines")),
+            "{e}"
+        );
     }
 }
@@ -0,0 +1,334 @@
+use base64::engine::general_purpose::STANDARD as b64;
+use base64::Engine; // trait
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Trust and client identity settings for the TLS connection to the SMTP server
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub ca_files: Vec<PathBuf>, // extra PEM CA bundles, e.g. a corporate CA
+    pub system_roots: bool,     // also trust the OS trust store
+    pub client_cert: Option<PathBuf>, // PEM chain for mutual TLS
+    pub client_key: Option<PathBuf>, // PEM private key for mutual TLS
+    pub pins_sha256: Vec<[u8; 32]>, // SHA-256 of a SubjectPublicKeyInfo in the server chain
+    // Lab debugging only: accept any certificate, pins are still enforced
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    pub fn client_config(&self) -> io::Result<rustls::ClientConfig> {
+        let builder = rustls::ClientConfig::builder();
+        let provider = builder.crypto_provider().clone();
+        let roots = Arc::new(self.root_store()?);
+        let builder = if self.pins_sha256.is_empty() && !self.danger_accept_invalid_certs {
+            builder.with_root_certificates(roots)
+        } else {
+            let webpki = if self.danger_accept_invalid_certs {
+                log::warn!(
+                    "TLS certificate validation DISABLED (smtp_tls_danger_accept_invalid_certs)"
+                );
+                None
+            } else {
+                Some(
+                    WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+                        .build()
+                        .map_err(io::Error::other)?,
+                )
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+                    webpki,
+                    pins: self.pins_sha256.clone(),
+                    provider,
+                }))
+        };
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                log::info!("Using TLS client certificate {}", cert.display());
+                builder
+                    .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            }
+            (None, None) => Ok(builder.with_no_client_auth()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS client certificate and key must both be set",
+            )),
+        }
+    }
+
+    fn root_store(&self) -> io::Result<RootCertStore> {
+        // root certificates used by Mozilla are always trusted
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if self.system_roots {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                log::warn!("System trust store: {}", e);
+            }
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            log::info!("Loaded {added} system root certificates, {ignored} ignored");
+        }
+        for path in &self.ca_files {
+            let (added, ignored) = roots.add_parsable_certificates(load_certs(path)?);
+            log::info!(
+                "Loaded {added} CA certificates from {}, {ignored} ignored",
+                path.display()
+            );
+            if added == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("No usable CA certificate in {}", path.display()),
+                ));
+            }
+        }
+        Ok(roots)
+    }
+}
+
+// Warn when a certificate in the chain expires sooner than this
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Log the negotiated TLS parameters and the peer certificate chain after the handshake
+pub fn log_session(connection: &rustls::ClientConnection, host: &str) {
+    log::info!("TLS session with {}", host);
+    log::info!(
+        "  protocol: {:?}, cipher suite: {:?}",
+        connection.protocol_version(),
+        connection.negotiated_cipher_suite().map(|s| s.suite())
+    );
+    // rustls does not send SNI when connecting by IP address
+    let sni_sent = host.parse::<std::net::IpAddr>().is_err();
+    log::info!(
+        "  ALPN: {}, SNI: {}",
+        connection
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).to_string())
+            .unwrap_or_else(|| "none".to_string()),
+        if sni_sent { host } else { "none (IP address)" }
+    );
+    let Some(chain) = connection.peer_certificates() else {
+        log::warn!("  server sent no certificate");
+        return;
+    };
+    let now = chrono::Utc::now().timestamp();
+    for (i, der) in chain.iter().enumerate() {
+        let cert = match x509_parser::parse_x509_certificate(der) {
+            Ok((_, cert)) => cert,
+            Err(e) => {
+                log::warn!("  [{i}] unparsable certificate: {e}");
+                continue;
+            }
+        };
+        let not_before = cert.validity().not_before.timestamp();
+        let not_after = cert.validity().not_after.timestamp();
+        log::info!("  [{i}] subject: {}", cert.subject());
+        log::info!("      issuer:  {}", cert.issuer());
+        let names = subject_alt_names(&cert);
+        if !names.is_empty() {
+            log::info!("      SANs:    {}", names.join(", "));
+        }
+        log::info!(
+            "      valid:   {} to {}",
+            format_timestamp(not_before),
+            format_timestamp(not_after)
+        );
+        log::info!("      SHA-256: {}", hex_fingerprint(&Sha256::digest(der)));
+        let days_left = (not_after - now) / 86400;
+        if not_after < now {
+            log::warn!("  [{i}] certificate EXPIRED {} days ago", -days_left);
+        } else if now < not_before {
+            log::warn!("  [{i}] certificate is not valid yet");
+        } else if days_left < EXPIRY_WARNING_DAYS {
+            log::warn!("  [{i}] certificate expires in {} days", days_left);
+        }
+        if i == 0 && !names.iter().any(|name| hostname_matches(name, host)) {
+            log::warn!("  [0] certificate does not match hostname {}", host);
+        }
+    }
+}
+
+fn subject_alt_names(cert: &x509_parser::certificate::X509Certificate<'_>) -> Vec<String> {
+    use x509_parser::extensions::GeneralName;
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return Vec::new();
+    };
+    san.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(dns) => Some(dns.to_string()),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+// "*.example.com" matches exactly one label, as in RFC 6125
+fn hostname_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == host,
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn hex_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Parse a pin given as "sha256/<base64>", plain base64 or 64 hex characters
+pub fn parse_pin(pin: &str) -> io::Result<[u8; 32]> {
+    let pin = pin.trim();
+    let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+    let bytes = if pin.len() == 64 && pin.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..32)
+            .map(|i| u8::from_str_radix(&pin[i * 2..i * 2 + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+    } else {
+        b64.decode(pin)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+    };
+    bytes.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("TLS pin is not a SHA-256 hash: {pin}"),
+        )
+    })
+}
+
+/// SHA-256 of the DER SubjectPublicKeyInfo, the value used for public key pinning
+pub fn spki_sha256(cert: &CertificateDer<'_>) -> io::Result<[u8; 32]> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).into())
+}
+
+fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to open {}: {}", path.display(), e),
+        )
+    })?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to open {}: {}", path.display(), e),
+        )
+    })?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key in {}", path.display()),
+        )
+    })
+}
+
+// Normal WebPKI validation (unless disabled) followed by the SPKI pin check
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+        for cert in std::iter::once(end_entity).chain(intermediates) {
+            let hash = spki_sha256(cert).map_err(|e| rustls::Error::General(e.to_string()))?;
+            if self.pins.contains(&hash) {
+                log::info!("TLS pin matched sha256/{}", b64.encode(hash));
+                return Ok(ServerCertVerified::assertion());
+            }
+        }
+        Err(rustls::Error::General(
+            "No certificate in the server chain matches a configured TLS pin".to_string(),
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}